@@ -0,0 +1,298 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use icu_collator::{Collator, CollatorOptions, Strength};
+use icu_locid::{locale, Locale};
+use unicode_normalization::UnicodeNormalization;
+use unicode_normalization::char::is_combining_mark;
+use crate::RsortError;
+
+/// The Unicode normalization applied to a text key before it is compared.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Normalization {
+    #[default]
+    None,
+    /// Canonical composition, so a precomposed `é` equals `e` followed by a combining accent.
+    Nfc,
+    /// Compatibility composition, which also folds ligatures, full-width forms and the like.
+    Nfkc,
+}
+
+impl Normalization {
+    pub fn apply(&self, text: &str) -> String {
+        match self {
+            Normalization::None => text.to_string(),
+            Normalization::Nfc => text.nfc().collect(),
+            Normalization::Nfkc => text.nfkc().collect(),
+        }
+    }
+}
+
+impl FromStr for Normalization {
+    type Err = RsortError;
+
+    fn from_str(normalization: &str) -> Result<Normalization, RsortError> {
+        match normalization {
+            "none" => Ok(Normalization::None),
+            "nfc" => Ok(Normalization::Nfc),
+            "nfkc" => Ok(Normalization::Nfkc),
+            _ => Err(RsortError::Parse(format!("unknown normalization: {}", normalization)))
+        }
+    }
+}
+
+impl fmt::Display for Normalization {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Normalization::None => write!(f, "none"),
+            Normalization::Nfc => write!(f, "nfc"),
+            Normalization::Nfkc => write!(f, "nfkc"),
+        }
+    }
+}
+
+/// Folds the full-width ASCII forms and the ideographic space of CJK text to their half-width
+/// counterparts, so `ＡＢＣ１２３` equals `ABC123`.
+pub fn fold_width(text: &str) -> String {
+    text.chars().map(|c| match c {
+        '\u{3000}' => ' ',
+        '\u{FF01}'..='\u{FF5E}' => char::from_u32(c as u32 - 0xFF01 + 0x21).unwrap_or(c),
+        _ => c
+    }).collect()
+}
+
+/// How text keys are ordered.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Collation {
+    /// The raw bytes, which is code point order for UTF-8.
+    #[default]
+    Binary,
+    /// Letters first compare without their case and accents, so `café` sorts between `cafe` and
+    /// `cafes`; the case and accents only break the ties. Latin letters are ordered as the CLDR root
+    /// collation orders them, so `ø` sorts after `o` and `ß` as `ss`, and come before the letters of
+    /// the other scripts, which are in code point order.
+    Unicode,
+    /// Like `unicode`, with Chinese characters ordered by their pinyin.
+    Pinyin,
+    /// Like `unicode`, with Chinese characters ordered by their stroke count.
+    Stroke,
+    /// Like `unicode`, with Chinese characters ordered by their Zhuyin (bopomofo) reading.
+    Zhuyin,
+}
+
+/// The code points a Chinese collation ranks: Bopomofo, the CJK Unified Ideographs with their
+/// extension A and the CJK Compatibility Ideographs.
+const HAN_RANGES: [(u32, u32); 4] = [(0x3100, 0x312F), (0x3400, 0x4DBF), (0x4E00, 0x9FFF), (0xF900, 0xFAFF)];
+
+/// The code points of the Latin letters ranked by the CLDR root collation: Basic Latin, Latin-1
+/// Supplement, Latin Extended-A and -B, IPA Extensions, Latin Extended Additional and the Latin
+/// ligatures.
+const LATIN_RANGES: [(u32, u32); 4] = [(0x41, 0x7A), (0xC0, 0x2AF), (0x1E00, 0x1EFF), (0xFB00, 0xFB06)];
+
+/// Past every code point, where the weights of Chinese characters, then of the Latin letters and
+/// last of the other letters start.
+const REORDERED: u32 = 0x110000;
+
+impl Collation {
+    /// The binary sort key of a text: its ordering is the ordering of the bytes, so the collation
+    /// is only paid once, when the key is extracted.
+    ///
+    /// The key holds a three-byte primary weight per character, ignoring case and accents, then the
+    /// decomposed lower-case text, which tells the accents apart, and last the text itself, which
+    /// tells the case apart; each level ends with zero bytes.
+    pub fn sort_key(&self, text: &str) -> Vec<u8> {
+        if *self == Collation::Binary {
+            return text.as_bytes().to_vec();
+        }
+        let han_ranks = self.han_ranks();
+        let han_count = han_ranks.map_or(0, |ranks| ranks.len() as u32);
+        let latin = latin_weights();
+        let latin_start = REORDERED + han_count;
+        let others_start = latin_start + latin.count;
+        let mut key = Vec::with_capacity(text.len() * 4 + 3);
+        let mut push = |weight: u32| key.extend_from_slice(&weight.to_be_bytes()[1..]);
+        for c in text.nfd().filter(|c| !is_combining_mark(*c)).flat_map(char::to_lowercase) {
+            // a Chinese collation puts the Chinese characters before the other letters, as CLDR does
+            if let Some(rank) = han_ranks.and_then(|ranks| ranks.get(&c)) {
+                push(REORDERED + rank);
+            } else if let Some(ranks) = latin.ranks.get(&c) {
+                ranks.iter().for_each(|rank| push(latin_start + rank));
+            } else if c.is_alphabetic() {
+                push(others_start + c as u32);
+            } else {
+                push(c as u32 + 1);
+            }
+        }
+        key.extend_from_slice(&[0, 0, 0]);
+        key.extend(text.nfd().flat_map(char::to_lowercase).collect::<String>().bytes());
+        key.push(0);
+        key.extend_from_slice(text.as_bytes());
+        key
+    }
+
+    /// The rank of every Chinese character under this collation, `None` when it is not a Chinese one.
+    fn han_ranks(&self) -> Option<&'static HashMap<char, u32>> {
+        static PINYIN: OnceLock<HashMap<char, u32>> = OnceLock::new();
+        static STROKE: OnceLock<HashMap<char, u32>> = OnceLock::new();
+        static ZHUYIN: OnceLock<HashMap<char, u32>> = OnceLock::new();
+        match self {
+            Collation::Binary | Collation::Unicode => None,
+            Collation::Pinyin => Some(PINYIN.get_or_init(|| rank_han(locale!("zh-u-co-pinyin")))),
+            Collation::Stroke => Some(STROKE.get_or_init(|| rank_han(locale!("zh-u-co-stroke")))),
+            Collation::Zhuyin => Some(ZHUYIN.get_or_init(|| rank_han(locale!("zh-u-co-zhuyin")))),
+        }
+    }
+}
+
+/// The primary weights of the Latin letters: their ranks under the CLDR root collation, letters
+/// differing only in case or accents sharing a rank, and a letter that sorts as two, like `ß` as
+/// `ss`, having the ranks of both.
+struct LatinWeights {
+    ranks: HashMap<char, Vec<u32>>,
+    count: u32,
+}
+
+fn latin_weights() -> &'static LatinWeights {
+    static LATIN: OnceLock<LatinWeights> = OnceLock::new();
+    LATIN.get_or_init(rank_latin)
+}
+
+/// Ranks the Latin letters by sorting them once with the root collation, comparing base letters only.
+fn rank_latin() -> LatinWeights {
+    let mut options = CollatorOptions::new();
+    options.strength = Some(Strength::Primary);
+    let collator = match Collator::try_new(&Default::default(), options) {
+        Ok(collator) => collator,
+        // the collation data is built in, so this does not happen; code point order is left then
+        Err(_) => return LatinWeights { ranks: HashMap::new(), count: 0 }
+    };
+    let mut chars: Vec<char> = LATIN_RANGES.iter()
+        .flat_map(|(first, last)| (*first..=*last).filter_map(char::from_u32))
+        .filter(|c| c.is_alphabetic())
+        .collect();
+    let (mut a, mut b) = ([0u8; 4], [0u8; 4]);
+    chars.sort_by(|x, y| collator.compare(x.encode_utf8(&mut a), y.encode_utf8(&mut b)));
+
+    let mut ranks = HashMap::with_capacity(chars.len());
+    let mut rank = 0;
+    for (i, c) in chars.iter().enumerate() {
+        if i > 0 && collator.compare(chars[i - 1].encode_utf8(&mut a), c.encode_utf8(&mut b)) != Ordering::Equal {
+            rank += 1;
+        }
+        ranks.insert(*c, vec![rank]);
+    }
+
+    // a letter sorting as two ASCII letters sorts right after the first of them, as `ß` after `s`
+    let mut base = None;
+    for c in chars.iter() {
+        if c.is_ascii_lowercase() {
+            base = Some(*c);
+            continue;
+        }
+        let base = match base {
+            Some(base) if ranks[c] != ranks[&base] => base,
+            _ => continue
+        };
+        let second = ('a'..='z').find(|second| {
+            collator.compare(c.encode_utf8(&mut a), &format!("{}{}", base, second)) == Ordering::Equal
+        });
+        if let Some(second) = second {
+            let expansion = vec![ranks[&base][0], ranks[&second][0]];
+            ranks.insert(*c, expansion);
+        }
+    }
+    LatinWeights { ranks, count: rank + 1 }
+}
+
+/// Ranks the Chinese characters by sorting them once with the CLDR collation of `locale`;
+/// characters the collation finds equal share a rank.
+fn rank_han(locale: Locale) -> HashMap<char, u32> {
+    let collator = match Collator::try_new(&locale.into(), CollatorOptions::new()) {
+        Ok(collator) => collator,
+        // the collation data is built in, so this does not happen; code point order is left then
+        Err(_) => return HashMap::new()
+    };
+    let mut chars: Vec<char> = HAN_RANGES.iter()
+        .flat_map(|(first, last)| (*first..=*last).filter_map(char::from_u32))
+        .collect();
+    let (mut a, mut b) = ([0u8; 4], [0u8; 4]);
+    chars.sort_by(|x, y| collator.compare(x.encode_utf8(&mut a), y.encode_utf8(&mut b)));
+
+    let mut ranks = HashMap::with_capacity(chars.len());
+    let mut rank = 0;
+    for (i, c) in chars.iter().enumerate() {
+        if i > 0 && collator.compare(chars[i - 1].encode_utf8(&mut a), c.encode_utf8(&mut b)) != Ordering::Equal {
+            rank += 1;
+        }
+        ranks.insert(*c, rank);
+    }
+    ranks
+}
+
+impl FromStr for Collation {
+    type Err = RsortError;
+
+    fn from_str(collation: &str) -> Result<Collation, RsortError> {
+        match collation {
+            "binary" => Ok(Collation::Binary),
+            "unicode" => Ok(Collation::Unicode),
+            "pinyin" => Ok(Collation::Pinyin),
+            "stroke" => Ok(Collation::Stroke),
+            "zhuyin" | "bopomofo" => Ok(Collation::Zhuyin),
+            _ => Err(RsortError::Parse(format!("unknown collation: {}", collation)))
+        }
+    }
+}
+
+impl fmt::Display for Collation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Collation::Binary => write!(f, "binary"),
+            Collation::Unicode => write!(f, "unicode"),
+            Collation::Pinyin => write!(f, "pinyin"),
+            Collation::Stroke => write!(f, "stroke"),
+            Collation::Zhuyin => write!(f, "zhuyin"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn collated(collation: Collation, texts: &[&str]) -> Vec<String> {
+        let mut keys: Vec<(Vec<u8>, &str)> = texts.iter().map(|text| (collation.sort_key(text), *text)).collect();
+        keys.sort();
+        keys.into_iter().map(|(_, text)| text.to_string()).collect()
+    }
+
+    #[test]
+    fn text_is_normalized_and_folded() {
+        assert_eq!(Normalization::Nfc.apply("e\u{301}"), "é");
+        assert_eq!(Normalization::Nfkc.apply("ﬁle"), "file");
+        assert_eq!(fold_width("ＡＢＣ　１２３"), "ABC 123");
+    }
+
+    #[test]
+    fn accents_and_case_only_break_ties() {
+        assert_eq!(collated(Collation::Unicode, &["cafes", "Café", "café", "cafe", "Cafe"]),
+                   vec!["Cafe", "cafe", "Café", "café", "cafes"]);
+        assert_eq!(collated(Collation::Binary, &["cafes", "café", "cafe"]), vec!["cafe", "cafes", "café"]);
+    }
+
+    #[test]
+    fn latin_letters_follow_the_root_collation() {
+        assert_eq!(collated(Collation::Unicode, &["zebra", "Straße", "strast", "strasse", "øre", "ore", "łódź", "lodz", "Ωmega"]),
+                   vec!["lodz", "łódź", "ore", "øre", "strasse", "Straße", "strast", "zebra", "Ωmega"]);
+        assert_eq!(collated(Collation::Unicode, &["afar", "Æble", "þorn", "ﬁlm", "film"]), vec!["Æble", "afar", "film", "ﬁlm", "þorn"]);
+    }
+
+    #[test]
+    fn chinese_sorts_by_reading_or_strokes() {
+        let texts = ["中", "文", "一", "阿", "大", "人", "a1", "1"];
+        assert_eq!(collated(Collation::Pinyin, &texts), vec!["1", "阿", "大", "人", "文", "一", "中", "a1"]);
+        assert_eq!(collated(Collation::Stroke, &texts), vec!["1", "一", "人", "大", "中", "文", "阿", "a1"]);
+    }
+}
@@ -0,0 +1,123 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::str::FromStr;
+use encoding_rs::{Encoding, UTF_8};
+use crate::{key_value, RsortError};
+
+/// The field naming the encoding of a GAIS record, read in the `auto` mode.
+pub const LANG_FIELD: &str = "@Lang:";
+
+/// How the bytes of a record are decoded before its keys are extracted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InputEncoding {
+    /// The keys are taken from the raw bytes, whatever their encoding.
+    #[default]
+    Raw,
+    /// Every record is in the given encoding, such as Big5 or GBK.
+    Fixed(&'static Encoding),
+    /// Each record is in the encoding its `@Lang:` field names, UTF-8 when it names none.
+    Auto,
+}
+
+impl InputEncoding {
+    /// The encoding of a record, or `None` when its bytes are taken as they are.
+    pub fn encoding_of(&self, record: &[u8]) -> Option<&'static Encoding> {
+        match self {
+            InputEncoding::Raw => None,
+            InputEncoding::Fixed(encoding) => Some(*encoding),
+            InputEncoding::Auto => Some(key_value(LANG_FIELD, record, None).ok()
+                .and_then(|label| Encoding::for_label(&label))
+                .unwrap_or(UTF_8)),
+        }
+    }
+
+    /// Decodes a record to UTF-8. The bytes are borrowed when they need no decoding; malformed
+    /// sequences are replaced with U+FFFD.
+    pub fn decode<'a>(&self, record: &'a [u8]) -> Cow<'a, [u8]> {
+        match self.encoding_of(record) {
+            None => Cow::Borrowed(record),
+            Some(encoding) => match encoding.decode_without_bom_handling(record).0 {
+                Cow::Borrowed(text) => Cow::Borrowed(text.as_bytes()),
+                Cow::Owned(text) => Cow::Owned(text.into_bytes())
+            }
+        }
+    }
+
+    /// A record decoded by [`InputEncoding::decode`], made ready to be written as UTF-8: a `@Lang:`
+    /// field naming the encoding it was decoded from names `utf-8` instead, so the output reads back
+    /// as what it is.
+    pub fn label_utf8(&self, decoded: Vec<u8>) -> Vec<u8> {
+        if self.encoding_of(&decoded).is_none_or(|encoding| encoding == UTF_8) {
+            return decoded;
+        }
+        let mut labelled = Vec::with_capacity(decoded.len());
+        for line in decoded.split_inclusive(|byte| *byte == b'\n') {
+            if line.starts_with(LANG_FIELD.as_bytes()) {
+                let value = line.trim_ascii_end();
+                labelled.extend_from_slice(LANG_FIELD.as_bytes());
+                labelled.extend_from_slice(b"utf-8");
+                labelled.extend_from_slice(&line[value.len()..]);
+            } else {
+                labelled.extend_from_slice(line);
+            }
+        }
+        labelled
+    }
+}
+
+impl FromStr for InputEncoding {
+    type Err = RsortError;
+
+    /// Reads `raw`, `auto` or an encoding label such as `big5`, `gbk` or `utf-8`.
+    fn from_str(label: &str) -> Result<InputEncoding, RsortError> {
+        match label {
+            "raw" => Ok(InputEncoding::Raw),
+            "auto" => Ok(InputEncoding::Auto),
+            _ => Encoding::for_label(label.as_bytes())
+                .map(InputEncoding::Fixed)
+                .ok_or_else(|| RsortError::Parse(format!("unknown input encoding: {}", label)))
+        }
+    }
+}
+
+impl fmt::Display for InputEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InputEncoding::Raw => write!(f, "raw"),
+            InputEncoding::Fixed(encoding) => write!(f, "{}", encoding.name()),
+            InputEncoding::Auto => write!(f, "auto"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_decode_to_utf8() {
+        let big5: InputEncoding = "big5".parse().unwrap();
+        assert_eq!(big5.decode(b"@title:\xa4\xa4\xa4\xe5\n"), "@title:中文\n".as_bytes());
+        assert_eq!("gbk".parse::<InputEncoding>().unwrap().decode(b"\xd6\xd0\xce\xc4"), "中文".as_bytes());
+        assert_eq!(InputEncoding::Raw.decode(b"\xa4\xa4"), &b"\xa4\xa4"[..]);
+        assert!("klingon".parse::<InputEncoding>().is_err());
+    }
+
+    #[test]
+    fn auto_follows_the_lang_field() {
+        let auto = InputEncoding::Auto;
+        assert_eq!(auto.decode(b"@Lang:big5\n@title:\xa4\xa4\xa4\xe5\n"), "@Lang:big5\n@title:中文\n".as_bytes());
+        assert_eq!(auto.decode(b"@Lang:gb2312\n@title:\xd6\xd0\xce\xc4\n"), "@Lang:gb2312\n@title:中文\n".as_bytes());
+        assert_eq!(auto.decode("@title:中文\n".as_bytes()), "@title:中文\n".as_bytes());
+    }
+
+    #[test]
+    fn transcoded_records_are_labelled_utf8() {
+        let auto = InputEncoding::Auto;
+        assert_eq!(auto.label_utf8("@Lang:big5\r\n@title:中文\n".as_bytes().to_vec()), "@Lang:utf-8\r\n@title:中文\n".as_bytes());
+        assert_eq!(auto.label_utf8(b"@Lang:UTF-8\n@title:a\n".to_vec()), b"@Lang:UTF-8\n@title:a\n");
+        let gbk: InputEncoding = "gbk".parse().unwrap();
+        assert_eq!(gbk.label_utf8(b"@Lang:gbk\n@title:a".to_vec()), b"@Lang:utf-8\n@title:a");
+        assert_eq!(gbk.label_utf8(b"@title:a\n".to_vec()), b"@title:a\n");
+    }
+}
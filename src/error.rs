@@ -0,0 +1,73 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Everything that can go wrong while sorting.
+#[derive(Debug)]
+pub enum RsortError {
+    /// An I/O failure, with the file or directory it happened on when known.
+    Io { path: Option<PathBuf>, source: io::Error },
+    /// A malformed value, such as a memory size or a command-line option.
+    Parse(String),
+    /// A record does not hold the field a key is extracted from.
+    MissingKey { key: String },
+    /// A record read from the input lacks a key whose missing-key policy is to abort.
+    RecordWithoutKey { key: String, path: PathBuf, offset: u64 },
+    /// An input given as sorted holds a record that sorts before the one preceding it.
+    Unsorted { path: PathBuf, previous: u64, offset: u64 },
+    /// A temporary run cannot be read back the way it was written.
+    CorruptedRun { path: PathBuf, detail: String },
+    /// The disk holding the given file or directory is full.
+    OutOfDiskSpace { path: PathBuf },
+    /// The sorter was set up inconsistently, e.g. without an output.
+    Config(String),
+}
+
+pub type Result<T> = std::result::Result<T, RsortError>;
+
+impl RsortError {
+    /// Wraps an I/O error together with the path it happened on.
+    /// A full disk is reported as [`RsortError::OutOfDiskSpace`].
+    pub fn io<P: AsRef<Path>>(path: P, source: io::Error) -> RsortError {
+        if source.kind() == io::ErrorKind::StorageFull {
+            RsortError::OutOfDiskSpace { path: path.as_ref().to_path_buf() }
+        } else {
+            RsortError::Io { path: Some(path.as_ref().to_path_buf()), source }
+        }
+    }
+}
+
+impl fmt::Display for RsortError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RsortError::Io { path: Some(path), source } => write!(f, "{}: {}", path.display(), source),
+            RsortError::Io { path: None, source } => write!(f, "{}", source),
+            RsortError::Parse(detail) => write!(f, "{}", detail),
+            RsortError::MissingKey { key } => write!(f, "record has no {} field", key),
+            RsortError::RecordWithoutKey { key, path, offset } =>
+                write!(f, "{}: the record at byte {} has no {} key", path.display(), offset, key),
+            RsortError::Unsorted { path, previous, offset } =>
+                write!(f, "{}: the record at byte {} sorts before the one at byte {}", path.display(), offset, previous),
+            RsortError::CorruptedRun { path, detail } =>
+                write!(f, "corrupted run {}: {}", path.display(), detail),
+            RsortError::OutOfDiskSpace { path } => write!(f, "{}: no space left on device", path.display()),
+            RsortError::Config(detail) => write!(f, "{}", detail),
+        }
+    }
+}
+
+impl Error for RsortError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RsortError::Io { source, .. } => Some(source),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for RsortError {
+    fn from(source: io::Error) -> RsortError {
+        RsortError::Io { path: None, source }
+    }
+}
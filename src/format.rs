@@ -0,0 +1,525 @@
+use std::io::{self, BufRead, Write};
+use std::sync::OnceLock;
+use regex::bytes::Regex;
+use crate::{contains, key_value, RecordFormat, Result, RsortError};
+
+/// The field noting where a GAIS record was read from.
+pub const SOURCE_FIELD: &str = "@Source:";
+
+/// The GAIS record format: a record starts at a line containing the begin pattern, such as
+/// `@Gais_REC:`, together with the lone `@` line just before it, and its fields are `@name:` lines.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GaisFormat {
+    begin: String,
+    body_field: Option<String>,
+}
+
+impl Default for GaisFormat {
+    fn default() -> GaisFormat {
+        GaisFormat::new("@Gais_REC:")
+    }
+}
+
+impl GaisFormat {
+    pub fn new(begin: &str) -> GaisFormat {
+        GaisFormat { begin: begin.to_string(), body_field: None }
+    }
+
+    /// The field opening the record body, such as `@body:`; the other fields are only looked for
+    /// before it.
+    pub fn body_field(mut self, body_field: &str) -> GaisFormat {
+        self.body_field = Some(body_field.to_string());
+        self
+    }
+}
+
+impl RecordFormat for GaisFormat {
+    fn split<'a>(&'a self, reader: Box<dyn BufRead + 'a>) -> Box<dyn Iterator<Item = io::Result<Vec<u8>>> + 'a> {
+        let begin = self.begin.as_bytes();
+        Box::new(LineGroups::new(reader, move |line: &[u8]| contains(line, begin), true))
+    }
+
+    fn field(&self, record: &[u8], name: &str) -> Option<Vec<u8>> {
+        key_value(name, record, self.body_field.as_deref()).ok()
+    }
+
+    fn default_keys(&self) -> &'static [&'static str] {
+        &["@url:", "@SiteCode:"]
+    }
+
+    /// Adds a `@Source:` field right after the begin line.
+    fn with_source(&self, record: &[u8], source: &str) -> Vec<u8> {
+        let mut start = 0;
+        for line in record.split_inclusive(|byte| *byte == b'\n') {
+            start += line.len();
+            if contains(line, self.begin.as_bytes()) {
+                break;
+            }
+        }
+        let mut annotated = Vec::with_capacity(record.len() + source.len() + 10);
+        annotated.extend_from_slice(&record[..start]);
+        if !annotated.is_empty() && !annotated.ends_with(b"\n") {
+            annotated.push(b'\n');
+        }
+        annotated.extend_from_slice(format!("{}{}\n", SOURCE_FIELD, source).as_bytes());
+        annotated.extend_from_slice(&record[start..]);
+        annotated
+    }
+
+    fn serialize(&self, record: &[u8], writer: &mut dyn Write) -> io::Result<()> {
+        write_terminated(record, b'\n', writer)
+    }
+}
+
+/// Records ended by a delimiter byte: one record per line, or NUL-delimited records as written by
+/// `find -print0`, which may span several lines.
+///
+/// The field `0` is the whole record and a number `N` its `N`th whitespace-separated column, as with
+/// `sort -k`; any other name is the rest of the first line of the record it starts, trimmed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DelimitedFormat {
+    delimiter: u8,
+}
+
+impl DelimitedFormat {
+    pub fn new(delimiter: u8) -> DelimitedFormat {
+        DelimitedFormat { delimiter }
+    }
+
+    /// One record per line.
+    pub fn lines() -> DelimitedFormat {
+        DelimitedFormat::new(b'\n')
+    }
+
+    /// Records ended by a NUL byte.
+    pub fn nul() -> DelimitedFormat {
+        DelimitedFormat::new(0)
+    }
+}
+
+impl RecordFormat for DelimitedFormat {
+    fn split<'a>(&'a self, reader: Box<dyn BufRead + 'a>) -> Box<dyn Iterator<Item = io::Result<Vec<u8>>> + 'a> {
+        split_at(reader, self.delimiter)
+    }
+
+    fn field(&self, record: &[u8], name: &str) -> Option<Vec<u8>> {
+        field_of(record.strip_suffix(&[self.delimiter]).unwrap_or(record), name)
+    }
+
+    fn default_keys(&self) -> &'static [&'static str] {
+        &["0"]
+    }
+
+    fn serialize(&self, record: &[u8], writer: &mut dyn Write) -> io::Result<()> {
+        write_terminated(record, self.delimiter, writer)
+    }
+}
+
+/// Multi-line records, each starting at a line the regular expression matches. The fields are
+/// found as in a [`DelimitedFormat`].
+#[derive(Clone, Debug)]
+pub struct RegexFormat {
+    begin: Regex,
+}
+
+impl RegexFormat {
+    pub fn new(pattern: &str) -> Result<RegexFormat> {
+        Regex::new(pattern)
+            .map(|begin| RegexFormat { begin })
+            .map_err(|error| RsortError::Parse(format!("invalid record pattern {}: {}", pattern, error)))
+    }
+}
+
+impl RecordFormat for RegexFormat {
+    fn split<'a>(&'a self, reader: Box<dyn BufRead + 'a>) -> Box<dyn Iterator<Item = io::Result<Vec<u8>>> + 'a> {
+        let begin = &self.begin;
+        Box::new(LineGroups::new(reader, move |line: &[u8]| {
+            begin.is_match(line.strip_suffix(b"\n").unwrap_or(line))
+        }, false))
+    }
+
+    fn field(&self, record: &[u8], name: &str) -> Option<Vec<u8>> {
+        field_of(record.strip_suffix(b"\n").unwrap_or(record), name)
+    }
+
+    fn default_keys(&self) -> &'static [&'static str] {
+        &["0"]
+    }
+
+    fn serialize(&self, record: &[u8], writer: &mut dyn Write) -> io::Result<()> {
+        write_terminated(record, b'\n', writer)
+    }
+}
+
+/// Comma- or tab-separated values. A field may be quoted with `"`, a doubled quote standing for
+/// one, and a quoted field may hold delimiters and newlines.
+///
+/// A column is named by its 1-based index, 0 standing for the whole row, or by its name in the
+/// header row: the first record of every input is the header unless told otherwise, and the header
+/// of the first input is the one whose names are used.
+#[derive(Debug)]
+pub struct CsvFormat {
+    delimiter: u8,
+    header: bool,
+    columns: OnceLock<Vec<Vec<u8>>>,
+}
+
+impl CsvFormat {
+    pub fn new(delimiter: u8) -> CsvFormat {
+        CsvFormat { delimiter, header: true, columns: OnceLock::new() }
+    }
+
+    /// Comma-separated values.
+    pub fn csv() -> CsvFormat {
+        CsvFormat::new(b',')
+    }
+
+    /// Tab-separated values.
+    pub fn tsv() -> CsvFormat {
+        CsvFormat::new(b'\t')
+    }
+
+    /// Whether the inputs open with a header row, as they do by default.
+    pub fn header(mut self, header: bool) -> CsvFormat {
+        self.header = header;
+        self
+    }
+
+    /// Splits a record into its fields, unquoted.
+    fn fields(&self, record: &[u8]) -> Vec<Vec<u8>> {
+        let record = line_of(record);
+        let mut fields = vec![Vec::new()];
+        let mut quoted = false;
+        let mut bytes = record.iter().peekable();
+        while let Some(byte) = bytes.next() {
+            let field = fields.last_mut().unwrap();
+            match *byte {
+                b'"' if quoted && bytes.peek() == Some(&&b'"') => {
+                    field.push(b'"');
+                    bytes.next();
+                },
+                b'"' => quoted = !quoted,
+                byte if byte == self.delimiter && !quoted => fields.push(Vec::new()),
+                byte => field.push(byte)
+            }
+        }
+        fields
+    }
+}
+
+impl RecordFormat for CsvFormat {
+    fn split<'a>(&'a self, mut reader: Box<dyn BufRead + 'a>) -> Box<dyn Iterator<Item = io::Result<Vec<u8>>> + 'a> {
+        Box::new(std::iter::from_fn(move || {
+            let mut record = Vec::new();
+            // a record only ends at a newline outside quotes, where the quotes seen are balanced
+            let mut quotes = 0;
+            loop {
+                let start = record.len();
+                match reader.read_until(b'\n', &mut record) {
+                    Ok(0) => break,
+                    Ok(_) => quotes += record[start..].iter().filter(|byte| **byte == b'"').count(),
+                    Err(error) => return Some(Err(error))
+                }
+                if quotes % 2 == 0 {
+                    break;
+                }
+            }
+            if record.is_empty() { None } else { Some(Ok(record)) }
+        }))
+    }
+
+    fn field(&self, record: &[u8], name: &str) -> Option<Vec<u8>> {
+        let column = match name.parse::<usize>() {
+            Ok(0) => return Some(line_of(record).to_vec()),
+            Ok(column) => column - 1,
+            Err(_) => self.columns.get()?.iter().position(|column| column.trim_ascii() == name.as_bytes())?
+        };
+        self.fields(record).into_iter().nth(column)
+    }
+
+    fn serialize(&self, record: &[u8], writer: &mut dyn Write) -> io::Result<()> {
+        write_terminated(record, b'\n', writer)
+    }
+
+    /// Adds a first `source` column.
+    fn with_source(&self, record: &[u8], source: &str) -> Vec<u8> {
+        let mut annotated = format!("\"{}\"", source.replace('"', "\"\"")).into_bytes();
+        annotated.push(self.delimiter);
+        annotated.extend_from_slice(record);
+        annotated
+    }
+
+    fn header_with_source(&self, header: &[u8]) -> Vec<u8> {
+        [b"source", &[self.delimiter][..], header].concat()
+    }
+
+    fn default_keys(&self) -> &'static [&'static str] {
+        &["0"]
+    }
+
+    fn take_header(&self, record: &[u8]) -> bool {
+        if self.header {
+            self.columns.get_or_init(|| self.fields(record));
+        }
+        self.header
+    }
+}
+
+/// JSON Lines: one JSON document per line.
+///
+/// A field is a path into the document such as `.meta.fetch_time` or `.links[0]`, a JSON pointer
+/// such as `/meta/fetch_time`, or the name of a top-level member. A string field is its unescaped
+/// text and a null one is missing; for a `json` key the field keeps its JSON type.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct JsonFormat;
+
+impl JsonFormat {
+    /// The value at `path` in a record, `None` when the record is not JSON or has no such value.
+    fn lookup(&self, record: &[u8], path: &str) -> Option<serde_json::Value> {
+        let mut document: serde_json::Value = serde_json::from_slice(record).ok()?;
+        document.pointer_mut(&json_pointer(path)).map(serde_json::Value::take)
+    }
+}
+
+impl RecordFormat for JsonFormat {
+    fn split<'a>(&'a self, reader: Box<dyn BufRead + 'a>) -> Box<dyn Iterator<Item = io::Result<Vec<u8>>> + 'a> {
+        split_at(reader, b'\n')
+    }
+
+    fn field(&self, record: &[u8], name: &str) -> Option<Vec<u8>> {
+        match self.lookup(record, name)? {
+            serde_json::Value::Null => None,
+            serde_json::Value::String(text) => Some(text.into_bytes()),
+            value => Some(value.to_string().into_bytes())
+        }
+    }
+
+    fn json_field(&self, record: &[u8], name: &str) -> Option<Vec<u8>> {
+        self.lookup(record, name).map(|value| value.to_string().into_bytes())
+    }
+
+    fn default_keys(&self) -> &'static [&'static str] {
+        &["."]
+    }
+
+    /// Adds a first `_source` member to an object; other documents get the source before them.
+    fn with_source(&self, record: &[u8], source: &str) -> Vec<u8> {
+        let start = record.len() - record.trim_ascii_start().len();
+        if record.get(start) != Some(&b'{') {
+            return [source.as_bytes(), b"\t", record].concat();
+        }
+        let rest = &record[start + 1..];
+        let separator: &[u8] = if rest.trim_ascii_start().starts_with(b"}") { b"" } else { b"," };
+        let member = format!("\"_source\":{}", serde_json::Value::from(source));
+        [&record[..start + 1], member.as_bytes(), separator, rest].concat()
+    }
+
+    fn serialize(&self, record: &[u8], writer: &mut dyn Write) -> io::Result<()> {
+        write_terminated(record, b'\n', writer)
+    }
+}
+
+/// Turns a path such as `.meta.links[0]` into the JSON pointer `/meta/links/0`; a pointer is kept
+/// as it is.
+fn json_pointer(path: &str) -> String {
+    if path.is_empty() || path.starts_with('/') {
+        return path.to_string();
+    }
+    let mut pointer = String::new();
+    for (i, member) in path.strip_prefix('.').unwrap_or(path).split('.').enumerate() {
+        let mut parts = member.split('[');
+        if let Some(name) = parts.next().filter(|name| !name.is_empty() || i > 0) {
+            pointer.push('/');
+            pointer.push_str(&name.replace('~', "~0").replace('/', "~1"));
+        }
+        for index in parts {
+            pointer.push('/');
+            pointer.push_str(index.trim_end_matches(']'));
+        }
+    }
+    pointer
+}
+
+/// Splits a stream into the records ended by `delimiter`.
+fn split_at<'a>(mut reader: Box<dyn BufRead + 'a>, delimiter: u8) -> Box<dyn Iterator<Item = io::Result<Vec<u8>>> + 'a> {
+    Box::new(std::iter::from_fn(move || {
+        let mut record = Vec::new();
+        match reader.read_until(delimiter, &mut record) {
+            Ok(0) => None,
+            Ok(_) => Some(Ok(record)),
+            Err(error) => Some(Err(error))
+        }
+    }))
+}
+
+/// The whole record, one of its columns or one of its fields.
+fn field_of(record: &[u8], name: &str) -> Option<Vec<u8>> {
+    match name.parse::<usize>() {
+        Ok(0) => Some(record.to_vec()),
+        Ok(column) => record.split(|byte| byte.is_ascii_whitespace())
+            .filter(|column| !column.is_empty())
+            .nth(column - 1)
+            .map(|column| column.to_vec()),
+        Err(_) => record.split(|byte| *byte == b'\n')
+            .find_map(|line| line.strip_prefix(name.as_bytes()))
+            .map(|value| value.trim_ascii().to_vec())
+    }
+}
+
+/// A row without its line ending.
+fn line_of(record: &[u8]) -> &[u8] {
+    let record = record.strip_suffix(b"\n").unwrap_or(record);
+    record.strip_suffix(b"\r").unwrap_or(record)
+}
+
+/// Writes a record, ending it with `terminator` when the last record of the input was not.
+fn write_terminated(record: &[u8], terminator: u8, writer: &mut dyn Write) -> io::Result<()> {
+    writer.write_all(record)?;
+    if !record.is_empty() && !record.ends_with(&[terminator]) {
+        writer.write_all(&[terminator])?;
+    }
+    Ok(())
+}
+
+/// Groups the lines of a stream into records, each starting at a line `is_begin` accepts.
+/// With `gais_marker`, a lone `@` line just before the begin line opens the record, as in GAIS.
+struct LineGroups<'a, F> {
+    reader: Box<dyn BufRead + 'a>,
+    is_begin: F,
+    gais_marker: bool,
+    record: Vec<u8>,
+    done: bool,
+}
+
+impl<'a, F: Fn(&[u8]) -> bool> LineGroups<'a, F> {
+    fn new(reader: Box<dyn BufRead + 'a>, is_begin: F, gais_marker: bool) -> LineGroups<'a, F> {
+        LineGroups { reader, is_begin, gais_marker, record: Vec::new(), done: false }
+    }
+}
+
+impl<'a, F: Fn(&[u8]) -> bool> Iterator for LineGroups<'a, F> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<io::Result<Vec<u8>>> {
+        let mut line = Vec::new();
+        while !self.done {
+            line.clear();
+            let read_size = match self.reader.read_until(b'\n', &mut line) {
+                Ok(read_size) => read_size,
+                Err(error) => return Some(Err(error))
+            };
+            if read_size > 0 && !(self.is_begin)(&line) {
+                self.record.extend_from_slice(&line);
+                continue;
+            }
+            // the record is complete when the next one begins or the stream ends
+            let mut next_record = Vec::new();
+            if read_size == 0 {
+                self.done = true;
+            } else {
+                if self.gais_marker && self.record.ends_with(b"@\n") &&
+                    (self.record.len() == 2 || self.record.ends_with(b"\n@\n")) {
+                    self.record.truncate(self.record.len() - 2);
+                    next_record.extend_from_slice(b"@\n");
+                }
+                next_record.extend_from_slice(&line);
+            }
+            let record = std::mem::replace(&mut self.record, next_record);
+            if !record.is_empty() {
+                return Some(Ok(record));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(format: &dyn RecordFormat, input: &[u8]) -> Vec<Vec<u8>> {
+        format.split(Box::new(input)).collect::<io::Result<_>>().unwrap()
+    }
+
+    #[test]
+    fn gais_records_open_with_their_marker() {
+        let input = b"junk\n@\n@Gais_REC:\n@url:a\n@\n@Gais_REC:\n@url:b";
+        assert_eq!(split(&GaisFormat::default(), input),
+                   vec![b"junk\n".to_vec(), b"@\n@Gais_REC:\n@url:a\n".to_vec(), b"@\n@Gais_REC:\n@url:b".to_vec()]);
+    }
+
+    #[test]
+    fn delimited_records_have_columns() {
+        let lines = DelimitedFormat::lines();
+        assert_eq!(split(&lines, b"b 2\na 1"), vec![b"b 2\n".to_vec(), b"a 1".to_vec()]);
+        assert_eq!(lines.field(b"  b   2\n", "2").unwrap(), b"2");
+        assert_eq!(lines.field(b"b 2\n", "0").unwrap(), b"b 2");
+        assert_eq!(lines.field(b"b 2\n", "3"), None);
+
+        let mut output = Vec::new();
+        lines.serialize(b"a 1", &mut output).unwrap();
+        assert_eq!(output, b"a 1\n");
+
+        let nul = DelimitedFormat::nul();
+        assert_eq!(split(&nul, b"x\ny\0z\0"), vec![b"x\ny\0".to_vec(), b"z\0".to_vec()]);
+        assert_eq!(nul.field(b"@name:x\n@size:3\0", "@size:").unwrap(), b"3");
+    }
+
+    #[test]
+    fn regex_records_span_lines() {
+        let format = RegexFormat::new(r"^From \S+@").unwrap();
+        let input = b"From a@x Mon\nSubject: one\nFrom b@y Tue\nSubject: two\n";
+        assert_eq!(split(&format, input),
+                   vec![b"From a@x Mon\nSubject: one\n".to_vec(), b"From b@y Tue\nSubject: two\n".to_vec()]);
+        assert_eq!(format.field(b"From b@y Tue\nSubject: two\n", "Subject:").unwrap(), b"two");
+        assert!(RegexFormat::new("(").is_err());
+    }
+
+    #[test]
+    fn csv_fields_are_unquoted() {
+        let csv = CsvFormat::csv();
+        let input = b"id,\"name\"\r\n1,\"Smith, \"\"J\"\"\nline two\"\r\n2,plain";
+        let records = split(&csv, input);
+        assert_eq!(records, vec![b"id,\"name\"\r\n".to_vec(), b"1,\"Smith, \"\"J\"\"\nline two\"\r\n".to_vec(), b"2,plain".to_vec()]);
+        assert!(csv.take_header(&records[0]));
+        assert_eq!(csv.field(&records[1], "name").unwrap(), b"Smith, \"J\"\nline two");
+        assert_eq!(csv.field(&records[2], "1").unwrap(), b"2");
+        assert_eq!(csv.field(&records[0], "0").unwrap(), b"id,\"name\"");
+        assert_eq!(csv.field(&records[2], "3"), None);
+        assert_eq!(csv.field(&records[2], "age"), None);
+
+        let tsv = CsvFormat::tsv().header(false);
+        assert!(!tsv.take_header(b"a\tb\n"));
+        assert_eq!(tsv.field(b"a,1\tb\n", "2").unwrap(), b"b");
+    }
+
+    #[test]
+    fn json_fields_follow_paths() {
+        assert_eq!(json_pointer(".meta.links[1]"), "/meta/links/1");
+        assert_eq!(json_pointer("/meta/fetch_time"), "/meta/fetch_time");
+        assert_eq!(json_pointer("id"), "/id");
+        assert_eq!(json_pointer("."), "");
+
+        let json = JsonFormat;
+        let record = br#"{"id": 7, "meta": {"title": "caf\u00e9", "links": ["a", "b"], "lang": null}}"#;
+        assert_eq!(json.field(record, ".meta.title").unwrap(), "café".as_bytes());
+        assert_eq!(json.field(record, ".meta.links[1]").unwrap(), b"b");
+        assert_eq!(json.field(record, "id").unwrap(), b"7");
+        assert_eq!(json.field(record, ".meta.lang"), None);
+        assert_eq!(json.json_field(record, ".meta.lang").unwrap(), b"null");
+        assert_eq!(json.json_field(record, ".meta.links[0]").unwrap(), br#""a""#);
+        assert_eq!(json.field(b"not json\n", "id"), None);
+    }
+
+    #[test]
+    fn sources_are_noted_in_the_format() {
+        assert_eq!(GaisFormat::default().with_source(b"@\n@Gais_REC:\n@url:a\n", "a.rec:0"),
+                   b"@\n@Gais_REC:\n@Source:a.rec:0\n@url:a\n");
+        let csv = CsvFormat::csv();
+        assert_eq!(csv.with_source(b"1,2\n", "a \"b\".csv:9"), b"\"a \"\"b\"\".csv:9\",1,2\n");
+        assert_eq!(csv.header_with_source(b"x,y\n"), b"source,x,y\n");
+        assert_eq!(JsonFormat.with_source(b"{\"a\":1}\n", "a.jsonl:0"), br#"{"_source":"a.jsonl:0","a":1}"#.iter().chain(b"\n").copied().collect::<Vec<u8>>());
+        assert_eq!(JsonFormat.with_source(b" { }", "x:1"), br#" {"_source":"x:1" }"#);
+        assert_eq!(DelimitedFormat::lines().with_source(b"b 2\n", "x:4"), b"x:4\tb 2\n");
+    }
+}
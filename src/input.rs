@@ -0,0 +1,84 @@
+use std::fs::read_dir;
+use std::path::{Path, PathBuf};
+use crate::{Result, RsortError, STDIN};
+
+/// Expands the inputs of a sort into the files they stand for, in order: a directory stands for
+/// every file under it and a glob pattern such as `crawl/*.rec` for every path it matches, both in
+/// path order. The standard input and paths that exist are kept as they are, and so is a path that
+/// is neither, so opening it reports it missing.
+pub fn expand_inputs(inputs: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::with_capacity(inputs.len());
+    for input in inputs {
+        if input.as_os_str() == STDIN || input.is_file() {
+            files.push(input.clone());
+        } else if input.is_dir() {
+            files_under(input, &mut files)?;
+        } else if is_pattern(input) {
+            let pattern = input.to_string_lossy();
+            let paths = glob::glob(&pattern)
+                .map_err(|error| RsortError::Parse(format!("invalid input pattern {}: {}", pattern, error)))?;
+            let before = files.len();
+            for path in paths {
+                let path = path.map_err(|error| {
+                    let path = error.path().to_path_buf();
+                    RsortError::io(path, error.into())
+                })?;
+                if path.is_dir() {
+                    files_under(&path, &mut files)?;
+                } else {
+                    files.push(path);
+                }
+            }
+            if files.len() == before {
+                return Err(RsortError::Config(format!("no input matches {}", pattern)));
+            }
+        } else {
+            files.push(input.clone());
+        }
+    }
+    Ok(files)
+}
+
+fn is_pattern(input: &Path) -> bool {
+    input.to_string_lossy().contains(['*', '?', '['])
+}
+
+/// Adds the files under a directory, its subdirectories included, in path order.
+fn files_under(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    let mut entries = Vec::new();
+    for entry in read_dir(dir).map_err(|error| RsortError::io(dir, error))? {
+        entries.push(entry.map_err(|error| RsortError::io(dir, error))?.path());
+    }
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            files_under(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    #[test]
+    fn directories_and_patterns_expand() {
+        let dir = std::env::temp_dir().join(format!("rsort_input_test_{}", std::process::id()));
+        create_dir_all(dir.join("b")).unwrap();
+        for file in ["a.rec", "c.rec", "notes.txt", "b/d.rec"].iter() {
+            write(dir.join(file), "").unwrap();
+        }
+
+        let expanded = expand_inputs(&[dir.join("*.rec"), PathBuf::from("-"), dir.join("b"), dir.join("gone.rec")]).unwrap();
+        assert_eq!(expanded, vec![dir.join("a.rec"), dir.join("c.rec"), PathBuf::from("-"), dir.join("b/d.rec"),
+                                  dir.join("gone.rec")]);
+        assert_eq!(expand_inputs(std::slice::from_ref(&dir)).unwrap(),
+                   vec![dir.join("a.rec"), dir.join("b/d.rec"), dir.join("c.rec"), dir.join("notes.txt")]);
+        assert!(expand_inputs(&[dir.join("*.gz")]).is_err());
+        remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,601 @@
+use std::cmp::Ordering;
+use std::convert::TryInto;
+use std::fmt;
+use std::str::FromStr;
+use crate::{fold_width, Collation, Normalization, RecordFormat, RsortError};
+
+/// The timestamp format of GAIS fields such as `@Fetchtime:` and `@post_time:`.
+pub const DEFAULT_TIME_FORMAT: &str = "%Y/%m/%d %H:%M:%S";
+
+/// How the value of a key field is interpreted for ordering.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KeyType {
+    /// Plain text, compared lexicographically.
+    Text,
+    /// A signed integer such as `-42`.
+    Integer,
+    /// A floating-point number such as `3.5e2`.
+    Float,
+    /// A human-readable size such as `89230`, `12K` or `1.5 GiB`, in powers of 1024.
+    Size,
+    /// A timestamp in the given `strftime`-like format, which knows `%Y`, `%m`, `%d`, `%H`, `%M`,
+    /// `%S` and `%%`.
+    Time(String),
+    /// Natural or version order: runs of digits compare as numbers, so `v1.10` sorts after `v1.9`.
+    Version,
+    /// A JSON value, ordered by its type first: null, then `false` and `true`, then the numbers by
+    /// value, the strings by their bytes and last arrays and objects. A value that is not JSON is
+    /// taken as a string.
+    Json,
+}
+
+impl KeyType {
+    /// Parses a field value into a key, or `None` when the value does not fit the type.
+    /// Text keeps the bytes as they are, whatever their encoding; the other types need UTF-8.
+    pub fn parse(&self, value: &[u8]) -> Option<Key> {
+        let value = value.trim_ascii();
+        let text = || std::str::from_utf8(value).ok().map(str::trim);
+        match self {
+            KeyType::Text => Some(Key::Text(value.to_vec())),
+            KeyType::Integer => text()?.parse::<i64>().ok().map(Key::Integer),
+            KeyType::Float => text()?.parse::<f64>().ok().filter(|float| !float.is_nan()).map(Key::Float),
+            KeyType::Size => parse_size(text()?).map(Key::Float),
+            KeyType::Time(format) => parse_time(text()?, format).map(Key::Integer),
+            KeyType::Version => Some(Key::Natural(natural_parts(text()?))),
+            KeyType::Json => Some(Key::Text(json_sort_key(value))),
+        }
+    }
+}
+
+impl FromStr for KeyType {
+    type Err = RsortError;
+
+    /// Reads `text`, `integer`, `float`, `size`, `time`, `time:FORMAT`, `version` or `json`.
+    fn from_str(key_type: &str) -> Result<KeyType, RsortError> {
+        match key_type {
+            "text" | "string" => Ok(KeyType::Text),
+            "integer" | "int" => Ok(KeyType::Integer),
+            "float" => Ok(KeyType::Float),
+            "size" => Ok(KeyType::Size),
+            "time" => Ok(KeyType::Time(DEFAULT_TIME_FORMAT.to_string())),
+            "version" | "natural" => Ok(KeyType::Version),
+            "json" => Ok(KeyType::Json),
+            _ => match key_type.strip_prefix("time:") {
+                Some(format) if !format.is_empty() => Ok(KeyType::Time(format.to_string())),
+                _ => Err(RsortError::Parse(format!("unknown key type: {}", key_type)))
+            }
+        }
+    }
+}
+
+impl fmt::Display for KeyType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyType::Text => write!(f, "text"),
+            KeyType::Integer => write!(f, "integer"),
+            KeyType::Float => write!(f, "float"),
+            KeyType::Size => write!(f, "size"),
+            KeyType::Time(format) => write!(f, "time:{}", format),
+            KeyType::Version => write!(f, "version"),
+            KeyType::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// What becomes of a record whose key is missing or does not parse as its type.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MissingKeyPolicy {
+    /// The record sorts before every record holding the key, whatever the direction of the key.
+    First,
+    /// The record sorts after every record holding the key, whatever the direction of the key.
+    Last,
+    /// The record is left out of the output.
+    Drop,
+    /// The record is written to the reject output instead of the sorted output.
+    Reject,
+    /// The sort fails, giving where the record is in the input.
+    Abort,
+}
+
+impl FromStr for MissingKeyPolicy {
+    type Err = RsortError;
+
+    fn from_str(policy: &str) -> Result<MissingKeyPolicy, RsortError> {
+        match policy {
+            "first" => Ok(MissingKeyPolicy::First),
+            "last" => Ok(MissingKeyPolicy::Last),
+            "drop" => Ok(MissingKeyPolicy::Drop),
+            "reject" => Ok(MissingKeyPolicy::Reject),
+            "abort" => Ok(MissingKeyPolicy::Abort),
+            _ => Err(RsortError::Parse(format!("unknown missing-key policy: {}", policy)))
+        }
+    }
+}
+
+impl fmt::Display for MissingKeyPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MissingKeyPolicy::First => write!(f, "first"),
+            MissingKeyPolicy::Last => write!(f, "last"),
+            MissingKeyPolicy::Drop => write!(f, "drop"),
+            MissingKeyPolicy::Reject => write!(f, "reject"),
+            MissingKeyPolicy::Abort => write!(f, "abort"),
+        }
+    }
+}
+
+/// A sort key: the field it is read from, how its value is ordered and in which direction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeySpec {
+    pub field: String,
+    pub key_type: KeyType,
+    /// Sorts the largest values first.
+    pub descending: bool,
+    /// Folds the value to lower case before it is parsed, so `ABC` and `abc` are equal keys.
+    pub ignore_case: bool,
+    /// The Unicode normalization of the value before it is parsed.
+    pub normalization: Normalization,
+    /// Folds the full-width forms of the value to half-width before it is parsed.
+    pub fold_width: bool,
+    /// How a text value is ordered, turned into a binary sort key when it is extracted.
+    pub collation: Collation,
+    /// What becomes of a record without this key, sorting it first by default.
+    pub missing: MissingKeyPolicy,
+}
+
+impl KeySpec {
+    /// An ascending, case-sensitive key.
+    pub fn new(field: &str, key_type: KeyType) -> KeySpec {
+        KeySpec {
+            field: field.to_string(),
+            key_type,
+            descending: false,
+            ignore_case: false,
+            normalization: Normalization::None,
+            fold_width: false,
+            collation: Collation::Binary,
+            missing: MissingKeyPolicy::First,
+        }
+    }
+
+    pub fn descending(mut self, descending: bool) -> KeySpec {
+        self.descending = descending;
+        self
+    }
+
+    pub fn ignore_case(mut self, ignore_case: bool) -> KeySpec {
+        self.ignore_case = ignore_case;
+        self
+    }
+
+    pub fn normalization(mut self, normalization: Normalization) -> KeySpec {
+        self.normalization = normalization;
+        self
+    }
+
+    pub fn fold_width(mut self, fold_width: bool) -> KeySpec {
+        self.fold_width = fold_width;
+        self
+    }
+
+    pub fn collation(mut self, collation: Collation) -> KeySpec {
+        self.collation = collation;
+        self
+    }
+
+    pub fn missing(mut self, missing: MissingKeyPolicy) -> KeySpec {
+        self.missing = missing;
+        self
+    }
+
+    /// Extracts and parses the key of a record laid out in `format`; `None` when the field is
+    /// missing or does not parse.
+    pub fn extract(&self, record: &[u8], format: &dyn RecordFormat) -> Option<Key> {
+        let value = match self.key_type {
+            KeyType::Json => format.json_field(record, &self.field)?,
+            _ => format.field(record, &self.field)?
+        };
+        match self.key_type.parse(&self.fold(value))? {
+            Key::Text(text) if self.key_type == KeyType::Text && self.collation != Collation::Binary =>
+                Some(Key::Text(self.collation.sort_key(&String::from_utf8_lossy(&text)))),
+            key => Some(key)
+        }
+    }
+
+    /// Normalizes the value and folds its width and case as asked for.
+    fn fold(&self, value: Vec<u8>) -> Vec<u8> {
+        if !self.ignore_case && !self.fold_width && self.normalization == Normalization::None {
+            return value;
+        }
+        match String::from_utf8(value) {
+            Ok(text) => {
+                let mut text = self.normalization.apply(&text);
+                if self.fold_width {
+                    text = fold_width(&text);
+                }
+                if self.ignore_case {
+                    text = text.to_lowercase();
+                }
+                text.into_bytes()
+            },
+            // only ASCII letters are folded in a value that is not UTF-8
+            Err(error) if self.ignore_case => error.into_bytes().to_ascii_lowercase(),
+            Err(error) => error.into_bytes()
+        }
+    }
+
+    /// Orders two values extracted by this key, honouring its direction; a missing value sorts last
+    /// under [`MissingKeyPolicy::Last`] and first otherwise.
+    pub fn compare(&self, a: &Option<Key>, b: &Option<Key>) -> Ordering {
+        let missing_last = self.missing == MissingKeyPolicy::Last;
+        match (a, b) {
+            (Some(a), Some(b)) if self.descending => b.cmp(a),
+            (Some(a), Some(b)) => a.cmp(b),
+            (None, None) => Ordering::Equal,
+            (None, Some(_)) if missing_last => Ordering::Greater,
+            (None, Some(_)) => Ordering::Less,
+            (Some(_), None) if missing_last => Ordering::Less,
+            (Some(_), None) => Ordering::Greater,
+        }
+    }
+}
+
+impl FromStr for KeySpec {
+    type Err = RsortError;
+
+    /// Reads `FIELD[,TYPE][,asc|desc][,ignore-case][,nfc|nfkc][,fold-width][,collate=COLLATION][,missing=POLICY]`,
+    /// such as `@Fetchtime:,time,desc` or `@title:,nfkc,collate=pinyin`.
+    fn from_str(spec: &str) -> Result<KeySpec, RsortError> {
+        let mut options = spec.split(',');
+        let field = options.next().unwrap_or_default();
+        if field.is_empty() {
+            return Err(RsortError::Parse(format!("the sort key has no field: {}", spec)));
+        }
+        let mut key = KeySpec::new(field, KeyType::Text);
+        for option in options {
+            match option {
+                "asc" => key.descending = false,
+                "desc" => key.descending = true,
+                "ignore-case" | "nocase" => key.ignore_case = true,
+                "case" => key.ignore_case = false,
+                "nfc" | "nfkc" => key.normalization = option.parse()?,
+                "fold-width" => key.fold_width = true,
+                _ => if let Some(policy) = option.strip_prefix("missing=") {
+                    key.missing = policy.parse()?;
+                } else if let Some(collation) = option.strip_prefix("collate=") {
+                    key.collation = collation.parse()?;
+                } else {
+                    key.key_type = option.parse()?;
+                }
+            }
+        }
+        Ok(key)
+    }
+}
+
+impl fmt::Display for KeySpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{},{}", self.field, self.key_type, if self.descending { "desc" } else { "asc" })?;
+        if self.ignore_case {
+            write!(f, ",ignore-case")?;
+        }
+        if self.normalization != Normalization::None {
+            write!(f, ",{}", self.normalization)?;
+        }
+        if self.fold_width {
+            write!(f, ",fold-width")?;
+        }
+        if self.collation != Collation::Binary {
+            write!(f, ",collate={}", self.collation)?;
+        }
+        if self.missing != MissingKeyPolicy::First {
+            write!(f, ",missing={}", self.missing)?;
+        }
+        Ok(())
+    }
+}
+
+/// One piece of a natural-order key: a run of digits or a run of anything else.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum NaturalPart {
+    /// The digit count without leading zeros, then the digits, so that longer numbers sort later.
+    Number(usize, String),
+    Text(String),
+}
+
+/// A key value parsed once when the record is read, so the comparisons stay cheap.
+#[derive(Clone, Debug)]
+pub enum Key {
+    /// The raw bytes of the value, compared bytewise.
+    Text(Vec<u8>),
+    Integer(i64),
+    Float(f64),
+    Natural(Vec<NaturalPart>),
+}
+
+impl Key {
+    fn rank(&self) -> u8 {
+        match self {
+            Key::Text(_) => 0,
+            Key::Integer(_) => 1,
+            Key::Float(_) => 2,
+            Key::Natural(_) => 3,
+        }
+    }
+
+    /// The binary form of the key stored in the run files.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.rank()];
+        match self {
+            Key::Text(text) => bytes.extend_from_slice(text),
+            Key::Integer(integer) => bytes.extend_from_slice(&integer.to_le_bytes()),
+            Key::Float(float) => bytes.extend_from_slice(&float.to_bits().to_le_bytes()),
+            Key::Natural(parts) => for part in parts {
+                let (tag, text) = match part {
+                    NaturalPart::Number(_, digits) => (0, digits),
+                    NaturalPart::Text(text) => (1, text),
+                };
+                bytes.push(tag);
+                bytes.extend_from_slice(&(text.len() as u32).to_le_bytes());
+                bytes.extend_from_slice(text.as_bytes());
+            }
+        }
+        bytes
+    }
+
+    /// Reads back a key written by [`Key::to_bytes`], or `None` when the bytes are malformed.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Key> {
+        let (rank, payload) = bytes.split_first()?;
+        match rank {
+            0 => Some(Key::Text(payload.to_vec())),
+            1 => payload.try_into().ok().map(|integer| Key::Integer(i64::from_le_bytes(integer))),
+            2 => payload.try_into().ok().map(|bits| Key::Float(f64::from_bits(u64::from_le_bytes(bits)))),
+            3 => {
+                let mut parts = Vec::new();
+                let mut rest = payload;
+                while let Some((tag, tail)) = rest.split_first() {
+                    if tail.len() < 4 {
+                        return None;
+                    }
+                    let len = u32::from_le_bytes(tail[..4].try_into().ok()?) as usize;
+                    let text = String::from_utf8(tail.get(4..4 + len)?.to_vec()).ok()?;
+                    parts.push(match tag {
+                        0 => NaturalPart::Number(text.len(), text),
+                        1 => NaturalPart::Text(text),
+                        _ => return None
+                    });
+                    rest = &tail[4 + len..];
+                }
+                Some(Key::Natural(parts))
+            },
+            _ => None
+        }
+    }
+}
+
+impl Ord for Key {
+    fn cmp(&self, other: &Key) -> Ordering {
+        match (self, other) {
+            (Key::Text(a), Key::Text(b)) => a.cmp(b),
+            (Key::Integer(a), Key::Integer(b)) => a.cmp(b),
+            (Key::Float(a), Key::Float(b)) => a.total_cmp(b),
+            (Key::Natural(a), Key::Natural(b)) => a.cmp(b),
+            _ => self.rank().cmp(&other.rank())
+        }
+    }
+}
+
+impl PartialOrd for Key {
+    fn partial_cmp(&self, other: &Key) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Key) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Key {}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Key::Text(text) => write!(f, "{}", String::from_utf8_lossy(text)),
+            Key::Integer(integer) => write!(f, "{}", integer),
+            Key::Float(float) => write!(f, "{}", float),
+            Key::Natural(parts) => {
+                for part in parts {
+                    match part {
+                        NaturalPart::Number(_, digits) if digits.is_empty() => write!(f, "0")?,
+                        NaturalPart::Number(_, digits) => write!(f, "{}", digits)?,
+                        NaturalPart::Text(text) => write!(f, "{}", text)?,
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Parses `1536`, `1.5K`, `2 MiB` or `3GB` into bytes, the suffixes being powers of 1024.
+fn parse_size(value: &str) -> Option<f64> {
+    let split = value.find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-' || c == '+'))
+        .unwrap_or(value.len());
+    let number = value[..split].parse::<f64>().ok().filter(|number| !number.is_nan())?;
+    let suffix = value[split..].trim().to_ascii_uppercase();
+    let suffix = suffix.strip_suffix("IB").or_else(|| suffix.strip_suffix('B')).unwrap_or(&suffix);
+    let exponent = match suffix {
+        "" => 0,
+        "K" => 1,
+        "M" => 2,
+        "G" => 3,
+        "T" => 4,
+        "P" => 5,
+        "E" => 6,
+        _ => return None
+    };
+    Some(number * 1024f64.powi(exponent))
+}
+
+/// Parses a timestamp in `format` into seconds since the Unix epoch.
+fn parse_time(value: &str, format: &str) -> Option<i64> {
+    let (mut year, mut month, mut day, mut hour, mut minute, mut second) = (1970, 1, 1, 0, 0, 0);
+    let mut rest = value;
+    let mut spec = format.chars();
+    while let Some(c) = spec.next() {
+        if c == '%' {
+            let (field, max_digits) = match spec.next()? {
+                'Y' => (&mut year, 4),
+                'm' => (&mut month, 2),
+                'd' => (&mut day, 2),
+                'H' => (&mut hour, 2),
+                'M' => (&mut minute, 2),
+                'S' => (&mut second, 2),
+                '%' => {
+                    rest = rest.strip_prefix('%')?;
+                    continue;
+                },
+                _ => return None
+            };
+            let digits = rest.chars().take(max_digits).take_while(|c| c.is_ascii_digit()).count();
+            if digits == 0 {
+                return None;
+            }
+            *field = rest[..digits].parse().ok()?;
+            rest = &rest[digits..];
+        } else if c.is_whitespace() {
+            let trimmed = rest.trim_start();
+            if trimmed.len() == rest.len() {
+                return None;
+            }
+            rest = trimmed;
+        } else {
+            rest = rest.strip_prefix(c)?;
+        }
+    }
+    if !rest.trim().is_empty() || !(1..=12).contains(&month) || !(1..=31).contains(&day) ||
+        hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    Some(days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second)
+}
+
+/// The number of days from 1970-01-01 to the given proleptic Gregorian date.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn natural_parts(value: &str) -> Vec<NaturalPart> {
+    let mut parts = Vec::new();
+    let mut rest = value;
+    while let Some(first) = rest.chars().next() {
+        let is_digit = first.is_ascii_digit();
+        let end = rest.find(|c: char| c.is_ascii_digit() != is_digit).unwrap_or(rest.len());
+        let (part, tail) = rest.split_at(end);
+        parts.push(if is_digit {
+            let digits = part.trim_start_matches('0');
+            NaturalPart::Number(digits.len(), digits.to_string())
+        } else {
+            NaturalPart::Text(part.to_string())
+        });
+        rest = tail;
+    }
+    parts
+}
+
+/// The binary sort key of a JSON value: a byte for its type, then its value in an order-preserving
+/// form, so keys of different types never compare their values.
+fn json_sort_key(value: &[u8]) -> Vec<u8> {
+    match serde_json::from_slice::<serde_json::Value>(value) {
+        Ok(serde_json::Value::Null) => vec![0],
+        Ok(serde_json::Value::Bool(boolean)) => vec![1, boolean as u8],
+        Ok(serde_json::Value::Number(number)) => {
+            // IEEE 754 bits compare as unsigned integers once the sign is folded in; adding zero
+            // turns -0.0 into 0.0
+            let bits = (number.as_f64().unwrap_or(0.0) + 0.0).to_bits();
+            let bits = if bits >> 63 == 1 { !bits } else { bits | 1 << 63 };
+            let mut key = vec![2];
+            key.extend_from_slice(&bits.to_be_bytes());
+            key
+        },
+        Ok(serde_json::Value::String(text)) => [&[3], text.as_bytes()].concat(),
+        Ok(other) => [&[4], other.to_string().as_bytes()].concat(),
+        Err(_) => [&[3], value].concat(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GaisFormat;
+
+    fn sorted(key_type: KeyType, values: &[&str]) -> Vec<String> {
+        let mut keys: Vec<(Key, &str)> = values.iter().map(|value| (key_type.parse(value.as_bytes()).unwrap(), *value)).collect();
+        keys.sort();
+        keys.into_iter().map(|(_, value)| value.to_string()).collect()
+    }
+
+    #[test]
+    fn typed_keys_sort_natively() {
+        assert_eq!(sorted(KeyType::Integer, &["100", "99", "-5"]), vec!["-5", "99", "100"]);
+        assert_eq!(sorted(KeyType::Float, &["1e3", "2.5", "-0.5"]), vec!["-0.5", "2.5", "1e3"]);
+        assert_eq!(sorted(KeyType::Size, &["2M", "900K", "89230", "1.5 GiB"]), vec!["89230", "900K", "2M", "1.5 GiB"]);
+        assert_eq!(sorted(KeyType::Version, &["v1.10", "v1.9", "v1.09.1", "v2"]), vec!["v1.9", "v1.09.1", "v1.10", "v2"]);
+        assert_eq!(sorted(KeyType::Json, &["\"10\"", "10", "-2.5", "null", "true", "[1]", "1e1", "\"9\"", "false", "-0"]),
+                   vec!["null", "false", "true", "-2.5", "-0", "10", "1e1", "\"10\"", "\"9\"", "[1]"]);
+        assert_eq!(sorted("time".parse().unwrap(), &["2017/01/10 23:15:09", "2016/06/17 00:00:00"]),
+                   vec!["2016/06/17 00:00:00", "2017/01/10 23:15:09"]);
+    }
+
+    #[test]
+    fn times_parse_in_custom_formats() {
+        let time: KeyType = "time:%Y-%m-%dT%H:%M".parse().unwrap();
+        assert_eq!(time.parse(b"1970-01-02T00:01"), Some(Key::Integer(86460)));
+        assert_eq!(KeyType::Time(DEFAULT_TIME_FORMAT.to_string()).parse(b"2017/01/10 23:15:09"),
+                   Some(Key::Integer(1484090109)));
+        assert_eq!(time.parse(b"2017/01/10"), None);
+        assert_eq!(KeyType::Integer.parse(b"12abc"), None);
+        assert_eq!(KeyType::Integer.parse(b"\xff12"), None);
+    }
+
+    #[test]
+    fn key_specs_parse_options() {
+        let gais = GaisFormat::default();
+        let key: KeySpec = "@Fetchtime:,time,desc".parse().unwrap();
+        assert_eq!(key, KeySpec::new("@Fetchtime:", "time".parse().unwrap()).descending(true));
+        assert_eq!(key.to_string().parse::<KeySpec>().unwrap(), key);
+        let key: KeySpec = "@title:,ignore-case".parse().unwrap();
+        assert_eq!(key.extract(b"@title:ETtoday\n", &gais), key.extract(b"@title:ettoday\n", &gais));
+        assert_eq!(key.compare(&None, &key.extract(b"@title:a\n", &gais)), Ordering::Less);
+        let key: KeySpec = "@Size:,integer,desc,missing=last".parse().unwrap();
+        assert_eq!(key.missing, MissingKeyPolicy::Last);
+        assert_eq!(key.to_string().parse::<KeySpec>().unwrap(), key);
+        assert_eq!(key.compare(&None, &Some(Key::Integer(1))), Ordering::Greater);
+        assert_eq!(key.compare(&Some(Key::Integer(2)), &Some(Key::Integer(1))), Ordering::Less);
+        assert!("@Size:,missing=maybe".parse::<KeySpec>().is_err());
+        let key: KeySpec = "@title:,nfkc,fold-width,collate=unicode".parse().unwrap();
+        assert_eq!(key.to_string().parse::<KeySpec>().unwrap(), key);
+        assert_eq!(key.extract("@title:ＣＡＦÉ\n".as_bytes(), &gais), key.extract("@title:CAFÉ\n".as_bytes(), &gais));
+        assert!(key.extract(b"@title:cafe\n", &gais) < key.extract("@title:café\n".as_bytes(), &gais));
+        assert!(key.extract("@title:café\n".as_bytes(), &gais) < key.extract(b"@title:cafes\n", &gais));
+        assert!(",desc".parse::<KeySpec>().is_err());
+        assert!("@Size:,bogus".parse::<KeySpec>().is_err());
+    }
+
+    #[test]
+    fn keys_round_trip_through_bytes() {
+        let keys = [Key::Text("修杰楷".as_bytes().to_vec()), Key::Text(b"\xad\xd7\xa9\xa3".to_vec()), Key::Integer(-42),
+                    Key::Float(1.5), KeyType::Version.parse(b"rsort-0.10.2b").unwrap()];
+        for key in keys.iter() {
+            assert_eq!(Key::from_bytes(&key.to_bytes()).as_ref(), Some(key));
+        }
+        assert_eq!(Key::from_bytes(&[1, 2, 3]), None);
+    }
+}
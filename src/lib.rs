@@ -1,8 +1,82 @@
 #![allow(unused)]
-use std::fs::{File, OpenOptions, create_dir_all};
+use std::fs::{File, OpenOptions, create_dir_all, remove_dir_all, remove_file};
+use std::borrow::Cow;
 use std::cmp::Ordering;
-use std::io::{BufRead, BufReader, LineWriter, Write, Read};
+use std::io::{BufRead, BufReader, ErrorKind, LineWriter, Write, Read};
 use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use rayon::prelude::*;
+
+mod collate;
+mod encoding;
+mod error;
+mod format;
+mod input;
+mod key;
+mod merge;
+mod run;
+mod select;
+mod temp;
+mod tree;
+mod unique;
+pub use collate::{fold_width, Collation, Normalization};
+pub use encoding::{InputEncoding, LANG_FIELD};
+pub use error::{RsortError, Result};
+pub use format::{CsvFormat, DelimitedFormat, GaisFormat, JsonFormat, RegexFormat, SOURCE_FIELD};
+pub use input::expand_inputs;
+pub use key::{Key, KeySpec, KeyType, MissingKeyPolicy, NaturalPart, DEFAULT_TIME_FORMAT};
+pub use merge::{max_fan_in, merge_runs, plan_merge_passes, SortedRun, DEFAULT_MERGE_BUFFER};
+pub use run::{RunReader, RunWriter};
+pub use select::ReplacementSelection;
+pub use temp::{remove_temp_dirs, TempDirs};
+pub use tree::LoserTree;
+pub use unique::{Dedup, Survivor};
+use unique::merge_unique;
+
+/// How records are laid out in the input: where each one ends, how its fields are found and how
+/// it is written back out. The sort itself only ever sees the records as opaque bytes.
+pub trait RecordFormat: Send + Sync {
+    /// Splits a stream into records; concatenated, the records give back the stream byte for byte.
+    fn split<'a>(&'a self, reader: Box<dyn BufRead + 'a>) -> Box<dyn Iterator<Item = std::io::Result<Vec<u8>>> + 'a>;
+
+    /// The value of the field `name` of a record, `None` when the record has no such field.
+    fn field(&self, record: &[u8], name: &str) -> Option<Vec<u8>>;
+
+    /// Writes a record out, ending it as the format ends its records should the input not have.
+    fn serialize(&self, record: &[u8], writer: &mut dyn Write) -> std::io::Result<()>;
+
+    /// The value of a field as JSON text, for a key of the `json` type. By default this is the
+    /// plain value, which the key takes as JSON when it parses as such and as a string otherwise.
+    fn json_field(&self, record: &[u8], name: &str) -> Option<Vec<u8>> {
+        self.field(record, name)
+    }
+
+    /// The record with a note of where it was read from, `source` being its input path and byte
+    /// offset as `path:offset`. By default the source goes before the record, followed by a tab.
+    fn with_source(&self, record: &[u8], source: &str) -> Vec<u8> {
+        [source.as_bytes(), b"\t", record].concat()
+    }
+
+    /// The header to write above records noted with their source; by default the header itself.
+    fn header_with_source(&self, header: &[u8]) -> Vec<u8> {
+        header.to_vec()
+    }
+
+    /// Offers the first record of an input as its header, returning whether it is one. A header is
+    /// left out of the sort, and the header of the first input is written at the top of the output.
+    fn take_header(&self, _record: &[u8]) -> bool {
+        false
+    }
+
+    /// The fields the records are sorted by when no key is given, such as the whole record; by
+    /// default none, and a sort without keys then fails.
+    fn default_keys(&self) -> &'static [&'static str] {
+        &[]
+    }
+}
 
 #[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
 pub struct Queue {
@@ -25,292 +99,1158 @@ impl Queue {
 
 #[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
 pub struct RawRecord {
-    pub record_key_value: Option<String>,
-    pub record_secondary_key_value: Option<String>,
-    pub raw_record: String,
+    /// The value of every sort key in order, `None` where the key is missing.
+    pub record_keys: Vec<Option<Key>>,
+    /// The record exactly as read from the input.
+    pub raw_record: Vec<u8>,
     pub record_size: usize,
-    pub record_end: bool,
+    /// Where the record was read from.
+    pub origin: RecordOrigin,
 }
 
 impl RawRecord {
-    pub fn new_raw_record() -> RawRecord {
+    /// Wraps a raw record and extracts its sort keys from the fields `format` finds. A missing key,
+    /// or one whose value does not parse as its type, is kept as `None`.
+    ///
+    /// The keys are extracted from the record decoded from `encoding`; with `to_utf8` the decoded
+    /// record also replaces the raw one, so it is written out as UTF-8, and its `@Lang:` field says so.
+    pub fn parse_raw_record(raw_record: Vec<u8>, origin: RecordOrigin, keys: &[KeySpec], format: &dyn RecordFormat,
+                            encoding: InputEncoding, to_utf8: bool) -> RawRecord {
+        let decoded = encoding.decode(&raw_record);
+        let record_keys = keys.iter().map(|key| key.extract(&decoded, format)).collect();
+        let raw_record = match decoded {
+            _ if !to_utf8 => raw_record,
+            Cow::Owned(decoded) => encoding.label_utf8(decoded),
+            Cow::Borrowed(_) => encoding.label_utf8(raw_record)
+        };
         RawRecord {
-            raw_record: "".to_string(),
-            record_size: 0,
-            record_key_value: None,
-            record_secondary_key_value: None,
-            record_end: true,
+            record_size: raw_record.len(),
+            record_keys,
+            raw_record,
+            origin,
         }
     }
 }
 
-#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
-pub struct InternalNode {
-    pub is_leaf: bool,
-    pub ptr: Option<usize> // None represent the node hasn't init.
+/// The input path that stands for the standard input.
+pub const STDIN: &str = "-";
+
+/// Where a record starts in the input: the index of its input file and its byte offset in that file.
+/// Origins order as the records come in the input.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct RecordOrigin {
+    pub file: usize,
+    pub offset: u64,
 }
 
-impl InternalNode {
-    pub fn new_leaf_inode() -> InternalNode {
-        InternalNode {
-            is_leaf: true,
-            ptr: None
+/// Parses a memory budget such as `512M`, `16G` or `65536` into bytes.
+/// The suffixes `K`, `M` and `G` (case insensitive, with an optional trailing `B`) are powers of 1024.
+pub fn parse_memory_size(size: &str) -> Result<usize> {
+    let size = size.trim();
+    let upper = size.to_ascii_uppercase();
+    let digits = upper.strip_suffix('B').unwrap_or(&upper);
+    let (digits, multiplier) = match digits.chars().last() {
+        Some('K') => (&digits[..digits.len() - 1], 1024),
+        Some('M') => (&digits[..digits.len() - 1], 1024 * 1024),
+        Some('G') => (&digits[..digits.len() - 1], 1024 * 1024 * 1024),
+        _ => (digits, 1)
+    };
+    match digits.parse::<usize>() {
+        Ok(0) => Err(RsortError::Parse(format!("memory size must be greater than zero: {}", size))),
+        Ok(value) => value.checked_mul(multiplier)
+            .ok_or_else(|| RsortError::Parse(format!("memory size is too large: {}", size))),
+        Err(_) => Err(RsortError::Parse(format!("invalid memory size: {}", size)))
+    }
+}
+
+/// Fetches the value of the field `pat` from a record.
+///
+/// The field only matches at the start of a line. Its value runs on over the following lines that do
+/// not open a field of their own with `@`, the lines being trimmed and joined with a space. When
+/// `body_field` is given, the search stops at the line opening the record body, so that text in the
+/// body is never taken for a field.
+pub fn key_value(pat: &str, record: &[u8], body_field: Option<&str>) -> Result<Vec<u8>> {
+    let mut lines = record.split(|byte| *byte == b'\n');
+    while let Some(line) = lines.next() {
+        if let Some(value) = line.strip_prefix(pat.as_bytes()) {
+            let mut value = value.trim_ascii().to_vec();
+            for continued in lines.take_while(|line| !line.starts_with(b"@")) {
+                let continued = continued.trim_ascii();
+                if !continued.is_empty() {
+                    if !value.is_empty() {
+                        value.push(b' ');
+                    }
+                    value.extend_from_slice(continued);
+                }
+            }
+            return Ok(value);
+        }
+        if body_field.is_some_and(|body_field| line.starts_with(body_field.as_bytes())) {
+            break;
         }
     }
+    Err(RsortError::MissingKey { key: pat.to_string() })
+}
 
-    pub fn new_non_leaf_inode() -> InternalNode {
-        InternalNode {
-            is_leaf: false,
-            ptr: None
+/// The record order shared by run generation and the merge: each key in turn, in its own direction,
+/// decides until one tells the records apart.
+pub fn compare_records(a: &RawRecord, b: &RawRecord, keys: &[KeySpec]) -> Ordering {
+    compare_keys(&a.record_keys, &b.record_keys, keys)
+}
+
+/// Orders the keys extracted from two records as [`compare_records`] orders the records.
+pub fn compare_keys(a: &[Option<Key>], b: &[Option<Key>], keys: &[KeySpec]) -> Ordering {
+    for (i, key) in keys.iter().enumerate() {
+        let ordering = key.compare(&a[i], &b[i]);
+        if ordering != Ordering::Equal {
+            return ordering;
         }
     }
+    Ordering::Equal
 }
 
-pub fn key_value(pat: &str, record: &str) -> Result<String, String> {
-    let record_inner = record.clone();
-    let record_inner: Vec<&str> = record_inner.split("\n").collect();
-    for context in record_inner {
-        if context.contains(pat) {
-            return Ok(String::from(&context[pat.len()..]))
+/// Sorts a chunk and writes it out as a run; in unique mode only the survivor of every group of
+/// records with the same keys is written.
+pub fn internal_pool_sort(internal_chunk_sort_pool: &mut [RawRecord],
+                          internal_chunk_count: usize,
+                          keys: &[KeySpec],
+                          unique: Option<&Survivor>,
+                          temp_dirs: &TempDirs) -> Result<()> {
+    internal_chunk_sort_pool.par_sort_by(|a, b| compare_records(a, b, keys));
+
+    let mut run = RunWriter::create(temp_dirs.run_path(internal_chunk_count))?;
+    match unique {
+        Some(survivor) => {
+            let mut dedup = Dedup::new(keys, survivor);
+            for record in internal_chunk_sort_pool.iter() {
+                if let Some(kept) = dedup.push(record) {
+                    run.write_record(kept)?;
+                }
+            }
+            if let Some(kept) = dedup.finish() {
+                run.write_record(kept)?;
+            }
+        },
+        None => for record in internal_chunk_sort_pool.iter() {
+            run.write_record(record)?;
         }
     }
-    Err("".to_string())
+    run.finish()?;
+    Ok(())
+}
+
+/// Whether `needle` occurs anywhere in `haystack`.
+pub(crate) fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    needle.is_empty() || haystack.windows(needle.len()).any(|window| window == needle)
+}
+
+/// What was read from the inputs besides their records.
+struct InputRead {
+    /// The header of the first input, when the format has headers.
+    header: Option<Vec<u8>>,
+    /// The number of bytes read, as the inputs may be streams of unknown size.
+    bytes: usize,
 }
 
-pub fn internal_pool_sort(internal_chunk_sort_pool: &mut Vec<RawRecord>, internal_chunk_count: usize){
-    internal_chunk_sort_pool.sort_by(|a, b|
-        {
-            if a.record_key_value.cmp(&b.record_key_value) != Ordering::Equal {
-                a.record_key_value.cmp(&b.record_key_value)
+/// Splits the inputs into records as `format` lays them out, and hands them to `f` in input order
+/// together with where they start. The records keep their bytes as they are, whatever their
+/// encoding.
+fn read_records<F>(inputs: &[PathBuf], format: &dyn RecordFormat, mut f: F) -> Result<InputRead>
+    where F: FnMut(Vec<u8>, RecordOrigin) -> Result<()> {
+    let mut read = InputRead { header: None, bytes: 0 };
+    // one input is open at a time, however many a directory or a pattern expands to
+    for (file_index, filename) in inputs.iter().enumerate() {
+        let file = open_input(filename)?;
+        let mut offset: u64 = 0;
+        for record in format.split(Box::new(BufReader::new(file))) {
+            let record = record.map_err(|error| RsortError::io(filename, error))?;
+            let record_size = record.len() as u64;
+            if offset == 0 && format.take_header(&record) {
+                read.header.get_or_insert(record);
             } else {
-                a.record_secondary_key_value.cmp(&b.record_secondary_key_value)
+                f(record, RecordOrigin { file: file_index, offset })?;
             }
-        }); // ASC default
-    println!("/tmp/rec_chunk_{:}", internal_chunk_count);
+            offset += record_size;
+        }
+        read.bytes += offset as usize;
+    }
+    Ok(read)
+}
+
+/// Applies the missing-key policies, keeping count of the records left out of the sort.
+struct MissingKeys<'a> {
+    keys: &'a [KeySpec],
+    format: &'a dyn RecordFormat,
+    inputs: &'a [PathBuf],
+    rejects: Option<Box<dyn Write + Send>>,
+    /// The number of records that went on to be sorted.
+    kept: usize,
+    dropped: usize,
+    rejected: usize,
+}
 
-    match create_dir_all(format!("/tmp/rec_chunk_{:}", internal_chunk_count)) {
-        Ok(file) => (),
-        Err(error) => {
-            panic!("Something error while creating temporary record directory. Details: {:?}", error);
+impl<'a> MissingKeys<'a> {
+    /// Whether the record goes on to be sorted. The first of its keys that is missing under a
+    /// `drop`, `reject` or `abort` policy decides its fate; `first` and `last` keep it.
+    fn keep(&mut self, record: &RawRecord) -> Result<bool> {
+        for (key, value) in self.keys.iter().zip(record.record_keys.iter()) {
+            if value.is_some() {
+                continue;
+            }
+            match key.missing {
+                MissingKeyPolicy::First | MissingKeyPolicy::Last => (),
+                MissingKeyPolicy::Drop => {
+                    self.dropped += 1;
+                    return Ok(false);
+                },
+                MissingKeyPolicy::Reject => {
+                    if let Some(rejects) = self.rejects.as_mut() {
+                        self.format.serialize(&record.raw_record, rejects)?;
+                    }
+                    self.rejected += 1;
+                    return Ok(false);
+                },
+                MissingKeyPolicy::Abort => return Err(RsortError::RecordWithoutKey {
+                    key: key.field.clone(),
+                    path: self.inputs[record.origin.file].clone(),
+                    offset: record.origin.offset,
+                })
+            }
         }
-    };
+        self.kept += 1;
+        Ok(true)
+    }
+}
 
-    let mut dir_set = 0;
+/// Hands the chunks read from the input to a background thread, which extracts their keys, sorts
+/// them and spills them as runs, so the next chunk is read while the previous one is being sorted.
+struct ChunkSpiller<'scope, 'env> {
+    scope: &'scope thread::Scope<'scope, 'env>,
+    thread_pool: &'env ThreadPool,
+    temp_dirs: &'env TempDirs,
+    keys: &'env [KeySpec],
+    /// The sort keys, followed in unique mode by the key of the survivor's field.
+    extracted: &'env [KeySpec],
+    unique: Option<&'env Survivor>,
+    format: &'env dyn RecordFormat,
+    encoding: InputEncoding,
+    to_utf8: bool,
+    missing_keys: &'env Mutex<MissingKeys<'env>>,
+    spilling: Option<thread::ScopedJoinHandle<'scope, Result<()>>>,
+    run_sizes: Vec<usize>,
+}
+
+impl<'scope, 'env> ChunkSpiller<'scope, 'env> {
+    fn spill(&mut self, chunk: Vec<(Vec<u8>, RecordOrigin)>, chunk_size: usize) -> Result<()> {
+        // only one chunk is sorted at a time, the sort itself being parallel
+        self.wait()?;
+        let internal_chunk_count = self.run_sizes.len();
+        self.run_sizes.push(chunk_size);
 
-    for i in 1..internal_chunk_sort_pool.len() / 10000 + 2 {
-        match create_dir_all(format!("/tmp/rec_chunk_{}/{}", internal_chunk_count, i)) {
-            Ok(file) => (),
-            Err(error) => {
-                panic!("Something error while creating temporary record directory. Details: {:?}", error);
+        let (thread_pool, temp_dirs) = (self.thread_pool, self.temp_dirs);
+        let (keys, extracted, unique) = (self.keys, self.extracted, self.unique);
+        let (format, missing_keys) = (self.format, self.missing_keys);
+        let (encoding, to_utf8) = (self.encoding, self.to_utf8);
+        self.spilling = Some(self.scope.spawn(move || thread_pool.install(|| {
+            let parsed: Vec<RawRecord> = chunk.into_par_iter()
+                .map(|(record, origin)| RawRecord::parse_raw_record(record, origin, extracted, format, encoding, to_utf8))
+                .collect();
+            // the records without their keys are screened in input order, so the rejects keep it
+            let mut missing_keys = missing_keys.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            let mut internal_chunk_sort_pool = Vec::with_capacity(parsed.len());
+            for record in parsed {
+                if missing_keys.keep(&record)? {
+                    internal_chunk_sort_pool.push(record);
+                }
             }
+            drop(missing_keys);
+            internal_pool_sort(&mut internal_chunk_sort_pool, internal_chunk_count, keys, unique, temp_dirs)
+        })));
+        Ok(())
+    }
+
+    fn wait(&mut self) -> Result<()> {
+        match self.spilling.take() {
+            Some(spilling) => spilling.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic)),
+            None => Ok(())
+        }
+    }
+
+    /// Waits for the last chunk to be spilled and returns the size of every run.
+    fn finish(mut self) -> Result<Vec<usize>> {
+        self.wait()?;
+        Ok(self.run_sizes)
+    }
+}
+
+/// An input that is sorted already, read as a run of the merge instead of being sorted again.
+struct InputRun<'a> {
+    path: &'a Path,
+    records: Box<dyn Iterator<Item = std::io::Result<Vec<u8>>> + 'a>,
+    /// Where the next record starts.
+    origin: RecordOrigin,
+    /// The first record, read ahead to tell whether it is a header.
+    first: Option<Vec<u8>>,
+    keys: &'a [KeySpec],
+    extracted: &'a [KeySpec],
+    format: &'a dyn RecordFormat,
+    encoding: InputEncoding,
+    to_utf8: bool,
+    missing_keys: &'a Mutex<MissingKeys<'a>>,
+    /// The keys and offset of the last record, kept when the input is verified to be sorted.
+    last: Option<(Vec<Option<Key>>, u64)>,
+    verify: bool,
+    pending: Option<RawRecord>,
+}
+
+impl<'a> InputRun<'a> {
+    /// Opens an input as a run, returning its header apart when the format has one.
+    #[allow(clippy::too_many_arguments)]
+    fn open(path: &'a Path, file: usize, input: Box<dyn Read>, keys: &'a [KeySpec], extracted: &'a [KeySpec],
+            format: &'a dyn RecordFormat, encoding: InputEncoding, to_utf8: bool, missing_keys: &'a Mutex<MissingKeys<'a>>,
+            verify: bool) -> Result<(InputRun<'a>, Option<Vec<u8>>)> {
+        let mut run = InputRun {
+            path,
+            records: format.split(Box::new(BufReader::new(input))),
+            origin: RecordOrigin { file, offset: 0 },
+            first: None,
+            keys,
+            extracted,
+            format,
+            encoding,
+            to_utf8,
+            missing_keys,
+            last: None,
+            verify,
+            pending: None,
         };
+        let mut header = None;
+        if let Some(first) = run.next_raw()? {
+            if format.take_header(&first) {
+                run.origin.offset += first.len() as u64;
+                header = Some(first);
+            } else {
+                run.first = Some(first);
+            }
+        }
+        Ok((run, header))
     }
-    for (idx, record) in internal_chunk_sort_pool.iter().enumerate() {
-        if idx % 10000 == 0 {
-            dir_set += 1;
-            match create_dir_all(format!("/tmp/rec_chunk_{}/{}", internal_chunk_count, dir_set)) {
-                Ok(file) => (),
-                Err(error) => {
-                    panic!("Something error while creating temporary record directory. Details: {:?}", error);
-                }
-            };
+
+    fn next_raw(&mut self) -> Result<Option<Vec<u8>>> {
+        match self.first.take() {
+            Some(first) => Ok(Some(first)),
+            None => self.records.next().transpose().map_err(|error| RsortError::io(self.path, error))
         }
-        let mut chunk_file = match OpenOptions::new()
-            .append(true)
-            .create(true)
-            .open(format!("/tmp/rec_chunk_{}/{}/rec_{:010}", internal_chunk_count, dir_set, idx)) {
-            Ok(file) => file,
-            Err(error) => {
-                panic!("Something error while creating temporary record file. Details: {:?}", error);
+    }
+
+    /// Reads on to the next record that is kept by the missing-key policies, checking its order
+    /// when the input is verified.
+    fn read_record(&mut self) -> Result<Option<RawRecord>> {
+        while let Some(raw) = self.next_raw()? {
+            let origin = self.origin;
+            self.origin.offset += raw.len() as u64;
+            let record = RawRecord::parse_raw_record(raw, origin, self.extracted, self.format, self.encoding, self.to_utf8);
+            if !self.missing_keys.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).keep(&record)? {
+                continue;
             }
-        };
-        chunk_file.write(&record.raw_record.as_bytes());
+            if self.verify {
+                if let Some((last, previous)) = &self.last {
+                    if compare_keys(&record.record_keys, last, self.keys) == Ordering::Less {
+                        return Err(RsortError::Unsorted { path: self.path.to_path_buf(), previous: *previous, offset: origin.offset });
+                    }
+                }
+                self.last = Some((record.record_keys.clone(), origin.offset));
+            }
+            return Ok(Some(record));
+        }
+        Ok(None)
     }
 }
 
-pub fn fill_the_queue(queue: &mut Queue,
-                      queue_dir_num: usize,
-                      queue_size: usize,
-                      primary_key_pat: &String,
-                      secondary_key_pat: &String) {
+impl<'a> SortedRun for InputRun<'a> {
+    fn peek(&mut self) -> Result<Option<&RawRecord>> {
+        if self.pending.is_none() {
+            self.pending = self.read_record()?;
+        }
+        Ok(self.pending.as_ref())
+    }
+
+    fn next_record(&mut self) -> Result<Option<RawRecord>> {
+        match self.pending.take() {
+            Some(record) => Ok(Some(record)),
+            None => self.read_record()
+        }
+    }
+}
+
+/// A run of a merge of sorted inputs: one of the inputs, or a temporary run that inputs were
+/// merged into by an earlier pass.
+enum MergeRun<'a> {
+    Input(InputRun<'a>),
+    Merged(RunReader),
+}
+
+impl<'a> MergeRun<'a> {
+    /// How many bytes of its input the run has read, none for a merged run.
+    fn input_bytes(&self) -> usize {
+        match self {
+            MergeRun::Input(run) => run.origin.offset as usize,
+            MergeRun::Merged(_) => 0
+        }
+    }
+}
+
+impl<'a> SortedRun for MergeRun<'a> {
+    fn peek(&mut self) -> Result<Option<&RawRecord>> {
+        match self {
+            MergeRun::Input(run) => run.peek(),
+            MergeRun::Merged(run) => SortedRun::peek(run)
+        }
+    }
+
+    fn next_record(&mut self) -> Result<Option<RawRecord>> {
+        match self {
+            MergeRun::Input(run) => run.next_record(),
+            MergeRun::Merged(run) => SortedRun::next_record(run)
+        }
+    }
+}
+
+/// Writes the sorted records out: whole, noted with their source, or only their primary key.
+struct Output<'a> {
+    sink: Box<dyn Write + Send>,
+    format: &'a dyn RecordFormat,
+    inputs: &'a [PathBuf],
+    key: &'a str,
+    keys_only: bool,
+    provenance: bool,
+    records: usize,
+}
+
+impl<'a> Output<'a> {
+    fn header(&mut self, header: &[u8]) -> Result<()> {
+        if self.keys_only {
+            return Ok(());
+        }
+        if self.provenance {
+            self.format.serialize(&self.format.header_with_source(header), &mut self.sink)?;
+        } else {
+            self.format.serialize(header, &mut self.sink)?;
+        }
+        Ok(())
+    }
+
+    fn record(&mut self, record: &RawRecord) -> Result<()> {
+        if self.keys_only {
+            // the field as written in the record, not its parsed form
+            let key = self.format.field(&record.raw_record, self.key).unwrap_or_default();
+            self.sink.write_all(&key)?;
+            self.sink.write_all(b"\n")?;
+        } else if self.provenance {
+            let source = format!("{}:{}", self.inputs[record.origin.file].display(), record.origin.offset);
+            self.format.serialize(&self.format.with_source(&record.raw_record, &source), &mut self.sink)?;
+        } else {
+            self.format.serialize(&record.raw_record, &mut self.sink)?;
+        }
+        self.records += 1;
+        Ok(())
+    }
+
+    /// Flushes the output and returns the number of records written.
+    fn finish(mut self) -> Result<usize> {
+        self.sink.flush()?;
+        Ok(self.records)
+    }
+}
+
+pub fn fill_the_queue<R: SortedRun>(queue: &mut Queue, run: &mut R, queue_size: usize) -> Result<()> {
     // fill the queue to full
-    while queue.end_of_record != true {
-
-        let mut record_files = match File::open(
-            format!("/tmp/rec_chunk_{}/{}/rec_{:010}",
-                    queue_dir_num,
-                    queue.record_cnt / 10000 + 1,
-                    queue.record_cnt)
-        ) {
-            Ok(rec_file) => rec_file,
-            Err(error) => {
+    while !queue.end_of_record {
+        let record_size = match run.peek()? {
+            Some(record) => record.record_size,
+            None => {
+                // the run has been drained
                 queue.end_of_record = true;
-                return;
+                return Ok(());
             }
         };
 
-        let record_file_meta = match record_files.metadata() {
-            Ok(file_meta) => file_meta,
-            Err(error) => {
-                panic!("Cannot read the metadata from the record file. Details: {:?}", error);
+        // an oversized record is still loaded into an empty queue, otherwise the run would stall
+        if queue.queue.is_empty() || (queue.current_size + record_size) < queue_size {
+            if let Some(record) = run.next_record()? {
+                queue.queue.push_back(record);
             }
-        };
-
-        if (queue.current_size + record_file_meta.len() as usize) < queue_size {
-            let mut record = String::new();
-            record_files.read_to_string(&mut record);
-            // parsing primary key
-            let mut primary_key_value = match key_value(
-                &primary_key_pat.as_str(),
-                &record.as_str()
-            ) {
-                Ok(str) => str,
-                Err(str) => str
-            };
-
-            // parsing secondary key
-            let mut secondary_key_value = match key_value(
-                &secondary_key_pat.as_str(),
-                &record.as_str()
-            ) {
-                Ok(str) => str,
-                Err(str) => str
-            };
-            &queue.queue.push_back(RawRecord {
-                raw_record: record,
-                record_size: record_file_meta.len() as usize,
-                record_key_value: Some(primary_key_value),
-                record_secondary_key_value: Some(secondary_key_value),
-                record_end: false
-            });
             queue.record_cnt += 1;
-            queue.current_size += record_file_meta.len() as usize;
+            queue.current_size += record_size;
         } else {
-            return ;
+            return Ok(());
         }
     }
+    Ok(())
 }
 
-pub fn winner_tree_by_idx(internal_node: &mut Vec<InternalNode>, external_node: &mut Vec<Box<Option<RawRecord>>>) -> usize {
-    // initialising the internal node leaf by looking up the external node
-    let mut i_tree_size = internal_node.len(); // internal tree size
-    let mut terminator_pos = i_tree_size;
-    let mut e_cur_cnt = 0;
-    for i in i_tree_size/2..i_tree_size {
-        if internal_node[i].is_leaf == true && internal_node[i].ptr == None {
-            let left_node = match &*external_node[e_cur_cnt] {
-                Some(rec) => { rec.clone() },
-                None => {RawRecord::new_raw_record()}
-            };
-            let right_node = match &*external_node[e_cur_cnt + 1] {
-                Some(rec) => { rec.clone() },
-                None => {RawRecord::new_raw_record()}
-            };
+/// What a finished sort went through.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SortSummary {
+    /// The number of records written to the output.
+    pub records: usize,
+    /// The number of sorted runs spilled to the temporary directory.
+    pub runs: usize,
+    /// The number of intermediate merges needed to bring the runs down to the fan-in.
+    pub intermediate_merges: usize,
+    /// The total size of the inputs in bytes, counted as they are read.
+    pub input_bytes: usize,
+    /// The number of records left out because a key with the `drop` policy was missing.
+    pub dropped: usize,
+    /// The number of records diverted to the reject output because a key with the `reject` policy
+    /// was missing.
+    pub rejected: usize,
+    /// The number of records left out in unique mode, having the same keys as the record kept.
+    pub duplicates: usize,
+}
 
-            if left_node.record_end == true && right_node.record_end == true {
-                internal_node[i].ptr = Some(terminator_pos);
-            } else if left_node.record_end == true && right_node.record_end == false {
-                internal_node[i].ptr = Some(e_cur_cnt + 1);
-            } else if left_node.record_end == false && right_node.record_end == true{
-                internal_node[i].ptr = Some(e_cur_cnt);
-            } else {
-                internal_node[i].ptr = Some(
-                    match left_node.record_key_value.cmp(&right_node.record_key_value) {
-                        Ordering::Equal => {
-                            match left_node.record_secondary_key_value.cmp(&right_node.record_secondary_key_value) {
-                                Ordering::Equal => { e_cur_cnt }, // left node
-                                Ordering::Greater => { e_cur_cnt + 1 }, // right node
-                                Ordering::Less => { e_cur_cnt } // left node
-                            }
-                        },
-                        Ordering::Greater => {
-                            e_cur_cnt + 1
-                        }, // right node
-                        Ordering::Less => {
-                            e_cur_cnt
-                        } // left node
-                    });
+/// What a finished check of the inputs' order found.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CheckSummary {
+    /// The number of records checked, up to the first one out of order.
+    pub records: usize,
+    /// The number of bytes read from the inputs checked.
+    pub input_bytes: usize,
+    /// The number of records left unchecked because a key with the `drop` policy was missing.
+    pub dropped: usize,
+    /// The number of records diverted to the reject output because a key with the `reject` policy
+    /// was missing.
+    pub rejected: usize,
+    /// The first pair of records out of order, `None` when every input is sorted.
+    pub disorder: Option<Disorder>,
+}
+
+/// Two consecutive records of an input that are out of order.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Disorder {
+    pub path: PathBuf,
+    /// Where the first record of the pair starts.
+    pub previous: u64,
+    /// Where the second record, which sorts before the first, starts.
+    pub offset: u64,
+    /// The fields of the keys, in the order they are compared.
+    pub fields: Vec<String>,
+    /// The keys of the first record as written in it, `None` for a missing one.
+    pub previous_keys: Vec<Option<String>>,
+    /// The keys of the second record.
+    pub keys: Vec<Option<String>>,
+}
+
+impl Disorder {
+    fn write_keys(&self, f: &mut std::fmt::Formatter<'_>, offset: u64, keys: &[Option<String>]) -> std::fmt::Result {
+        write!(f, "\n  byte {}:", offset)?;
+        for (i, (field, key)) in self.fields.iter().zip(keys.iter()).enumerate() {
+            let separator = if i == 0 { "" } else { "," };
+            match key {
+                Some(key) => write!(f, "{} {} {:?}", separator, field, key)?,
+                None => write!(f, "{} {} missing", separator, field)?,
             }
         }
-        e_cur_cnt += 2;
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Disorder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: the record at byte {} sorts before the one at byte {}", self.path.display(), self.offset,
+               self.previous)?;
+        self.write_keys(f, self.previous, &self.previous_keys)?;
+        self.write_keys(f, self.offset, &self.keys)
+    }
+}
+
+/// An external merge sort over record files, GAIS records unless another [`RecordFormat`] is given.
+///
+/// ```no_run
+/// use std::fs::File;
+/// use rsort::Sorter;
+///
+/// let summary = Sorter::new()
+///     .input("ettoday.rec")
+///     .key("@url:")
+///     .secondary_key("@SiteCode:")
+///     .memory_size(512 * 1024 * 1024)
+///     .output(File::create("ettoday.sorted")?)
+///     .sort()?;
+/// println!("{} records sorted", summary.records);
+/// # Ok::<(), rsort::RsortError>(())
+/// ```
+pub struct Sorter {
+    inputs: Vec<PathBuf>,
+    rec_begin_pat: String,
+    keys: Vec<KeySpec>,
+    keys_given: bool,
+    body_field: Option<String>,
+    format: Option<Box<dyn RecordFormat>>,
+    encoding: InputEncoding,
+    to_utf8: bool,
+    memory_size: usize,
+    fan_in: Option<usize>,
+    merge_buffer: usize,
+    threads: usize,
+    replacement_selection: bool,
+    merge: bool,
+    verify_sorted: bool,
+    temp_dirs: Vec<PathBuf>,
+    keys_only: bool,
+    provenance: bool,
+    unique: Option<Survivor>,
+    output: Option<Box<dyn Write + Send>>,
+    rejects: Option<Box<dyn Write + Send>>,
+}
+
+impl Default for Sorter {
+    fn default() -> Sorter {
+        Sorter::new()
+    }
+}
+
+impl Sorter {
+    pub fn new() -> Sorter {
+        Sorter {
+            inputs: Vec::new(),
+            rec_begin_pat: String::from("@Gais_REC:"),
+            keys: vec![KeySpec::new("@url:", KeyType::Text), KeySpec::new("@SiteCode:", KeyType::Text)],
+            keys_given: false,
+            body_field: None,
+            format: None,
+            encoding: InputEncoding::Raw,
+            to_utf8: false,
+            memory_size: 512 * 1024 * 1024,
+            fan_in: None,
+            merge_buffer: DEFAULT_MERGE_BUFFER,
+            threads: 0,
+            replacement_selection: false,
+            merge: false,
+            verify_sorted: false,
+            temp_dirs: Vec::new(),
+            keys_only: false,
+            provenance: false,
+            unique: None,
+            output: None,
+            rejects: None,
+        }
+    }
+
+    /// Adds a record file to sort; the inputs are sorted together, as one. A directory adds every
+    /// file under it and a glob pattern such as `crawl/*.rec` every file it matches, in path order.
+    /// The path [`STDIN`], `-`, reads the standard input, so the sort can sit in a pipeline: its
+    /// runs are cut as the input comes in, without knowing its size up front.
+    pub fn input<P: AsRef<Path>>(mut self, path: P) -> Sorter {
+        self.inputs.push(path.as_ref().to_path_buf());
+        self
     }
 
-    i_tree_size /= 2;
+    /// The line pattern marking the beginning of a GAIS record.
+    pub fn record_delimiter(mut self, pat: &str) -> Sorter {
+        self.rec_begin_pat = pat.to_string();
+        self
+    }
 
-    // performing the sibling comparision in the internal non-leaf node
-    // We promise the leaf does not contain the None.
-    while i_tree_size > 1 {
-        for i in i_tree_size/2..i_tree_size {
-            let left_node_idx = match internal_node[i*2].ptr {
-                Some(idx) =>  { idx },
-                None => { panic!("The leaf node contains the None initialised value.") }
-            };
-            let right_node_idx = match internal_node[i*2+1].ptr {
-                Some(idx) =>  { idx },
-                None => { panic!("The leaf node contains the None initialised value.") }
-            };
+    /// The field pattern of the primary sort key. Without any key given, the records are sorted by
+    /// the default keys of their format: `@url:` then `@SiteCode:` for GAIS records, the whole
+    /// record otherwise.
+    pub fn key(mut self, pat: &str) -> Sorter {
+        self.keys[0].field = pat.to_string();
+        self.keys_given = true;
+        self
+    }
 
-            if internal_node[i].is_leaf == false && internal_node[i].ptr == None {
-                let left_node = match &*external_node[left_node_idx] {
-                    Some(rec) => { rec.clone() },
-                    None => {RawRecord::new_raw_record()}
-                };
-                let right_node = match &*external_node[right_node_idx] {
-                    Some(rec) => { rec.clone() },
-                    None => {RawRecord::new_raw_record()}
+    /// How the primary key is ordered, as text by default.
+    pub fn key_type(mut self, key_type: KeyType) -> Sorter {
+        self.keys[0].key_type = key_type;
+        self
+    }
+
+    /// The field pattern of the secondary sort key, compared when the primary keys are equal.
+    pub fn secondary_key(mut self, pat: &str) -> Sorter {
+        self.secondary_key_spec().field = pat.to_string();
+        self.keys_given = true;
+        self
+    }
+
+    /// How the secondary key is ordered, as text by default.
+    pub fn secondary_key_type(mut self, key_type: KeyType) -> Sorter {
+        self.secondary_key_spec().key_type = key_type;
+        self
+    }
+
+    fn secondary_key_spec(&mut self) -> &mut KeySpec {
+        if self.keys.len() < 2 {
+            self.keys.push(KeySpec::new("@SiteCode:", KeyType::Text));
+        }
+        &mut self.keys[1]
+    }
+
+    /// Replaces the primary and secondary keys with any number of keys, each compared in turn when
+    /// the ones before it are equal. An empty list keeps the current keys.
+    pub fn keys(mut self, keys: Vec<KeySpec>) -> Sorter {
+        if !keys.is_empty() {
+            self.keys = keys;
+            self.keys_given = true;
+        }
+        self
+    }
+
+    /// The field opening the body of a GAIS record, such as `@body:`. The keys are only looked for
+    /// in the fields before it, so text in the body that looks like a field is never taken for a key.
+    pub fn body_field(mut self, pat: &str) -> Sorter {
+        self.body_field = Some(pat.to_string());
+        self
+    }
+
+    /// Reads the records in another format than GAIS, such as lines or NUL-delimited records; the
+    /// record delimiter and body field then have no effect.
+    pub fn format<F: RecordFormat + 'static>(mut self, format: F) -> Sorter {
+        self.format = Some(Box::new(format));
+        self
+    }
+
+    /// How the records are decoded before their keys are extracted, such as Big5 or GBK, or
+    /// [`InputEncoding::Auto`] to follow the `@Lang:` field of each record. By default the keys are
+    /// taken from the raw bytes.
+    pub fn input_encoding(mut self, encoding: InputEncoding) -> Sorter {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Writes the records decoded to UTF-8 instead of in their input encoding, rewriting a `@Lang:`
+    /// field to `utf-8`. The sort fails when no input encoding was given to decode from.
+    pub fn output_utf8(mut self, to_utf8: bool) -> Sorter {
+        self.to_utf8 = to_utf8;
+        self
+    }
+
+    /// The memory budget in bytes for run generation. One chunk of the input is read while the
+    /// previous one is sorted, so a run holds at most half of the budget.
+    pub fn memory_size(mut self, memory_size: usize) -> Sorter {
+        self.memory_size = memory_size.max(1);
+        self
+    }
+
+    /// Caps the number of runs merged at once. Without a cap the fan-in is only limited by the
+    /// memory budget and the merge buffer; more runs than that are merged in several passes.
+    pub fn fan_in(mut self, fan_in: usize) -> Sorter {
+        self.fan_in = Some(fan_in.max(2));
+        self
+    }
+
+    /// The smallest read-ahead buffer in bytes each run gets during a merge, which bounds the fan-in
+    /// to the memory budget divided by this buffer.
+    pub fn merge_buffer(mut self, merge_buffer: usize) -> Sorter {
+        self.merge_buffer = merge_buffer.max(1);
+        self
+    }
+
+    /// The number of threads extracting keys and sorting the runs; 0, the default, uses one per CPU.
+    pub fn threads(mut self, threads: usize) -> Sorter {
+        self.threads = threads;
+        self
+    }
+
+    /// Generates the runs by replacement selection instead of sorting memory-sized chunks. The runs
+    /// of a random input come out about twice the memory budget, and a partially sorted input gives
+    /// far longer runs, down to a single run for a sorted input, at the cost of a sequential sort.
+    pub fn replacement_selection(mut self, replacement_selection: bool) -> Sorter {
+        self.replacement_selection = replacement_selection;
+        self
+    }
+
+    /// Merges inputs that are each sorted already, as `sort -m` does: every input is a run of a single
+    /// merge, so nothing is spilled to the temporary directories. Inputs that are not sorted give
+    /// an unsorted output, unless their order is verified.
+    pub fn merge(mut self, merge: bool) -> Sorter {
+        self.merge = merge;
+        self
+    }
+
+    /// Verifies while merging that every input is sorted, failing with [`RsortError::Unsorted`] at
+    /// the first record out of order.
+    pub fn verify_sorted(mut self, verify_sorted: bool) -> Sorter {
+        self.verify_sorted = verify_sorted;
+        self
+    }
+
+    /// Adds a directory to hold the temporary sorted runs; with several directories the runs are
+    /// spread over them round-robin. Without any, the system temporary directory (`TMPDIR`) is used.
+    pub fn temp_dir<P: AsRef<Path>>(mut self, path: P) -> Sorter {
+        self.temp_dirs.push(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Writes only the primary key of each sorted record instead of the whole record.
+    pub fn keys_only(mut self, keys_only: bool) -> Sorter {
+        self.keys_only = keys_only;
+        self
+    }
+
+    /// Notes in every record written where it was read from, its input file and byte offset, in the
+    /// way of the format: a `@Source:` field for GAIS records, a `source` column for CSV, a `_source`
+    /// member for JSON objects, and a tab-separated prefix otherwise.
+    pub fn provenance(mut self, provenance: bool) -> Sorter {
+        self.provenance = provenance;
+        self
+    }
+
+    /// Keeps only one record of the records with the same keys, the one `survivor` picks. The
+    /// duplicates are already left out of every run, and of every merge of the runs.
+    pub fn unique(mut self, survivor: Survivor) -> Sorter {
+        self.unique = Some(survivor);
+        self
+    }
+
+    /// Where the sorted records are written.
+    pub fn output<W: Write + Send + 'static>(mut self, sink: W) -> Sorter {
+        self.output = Some(Box::new(sink));
+        self
+    }
+
+    /// Where the records missing a key with the `reject` policy are written, in input order.
+    pub fn rejects<W: Write + Send + 'static>(mut self, sink: W) -> Sorter {
+        self.rejects = Some(Box::new(sink));
+        self
+    }
+
+    /// Performs the external sort: splits the inputs into sorted runs no larger than the memory budget,
+    /// then merges the runs into the output.
+    pub fn sort(mut self) -> Result<SortSummary> {
+        self.default_keys()?;
+        let result_file = match self.output.take() {
+            Some(sink) => sink,
+            None => return Err(RsortError::Config("no output was given to the sorter".to_string()))
+        };
+        let keys = self.keys.as_slice();
+        let gais = self.gais_format();
+        let format: &dyn RecordFormat = self.format.as_deref().unwrap_or(&gais);
+        let (encoding, to_utf8) = (self.encoding, self.to_utf8);
+        let unique = self.unique.as_ref();
+        let extracted: Vec<KeySpec> = keys.iter().chain(unique.and_then(Survivor::key)).cloned().collect();
+        if to_utf8 && encoding == InputEncoding::Raw {
+            return Err(RsortError::Config("the records are to be written as UTF-8, but no input encoding was given to decode them from".to_string()));
+        }
+        if self.rejects.is_none() && keys.iter().any(|key| key.missing == MissingKeyPolicy::Reject) {
+            return Err(RsortError::Config("a key rejects the records without it, but no reject output was given".to_string()));
+        }
+        let inputs = expand_inputs(&self.inputs)?;
+        let missing_keys = Mutex::new(MissingKeys {
+            keys,
+            format,
+            inputs: &inputs,
+            rejects: self.rejects.take(),
+            kept: 0,
+            dropped: 0,
+            rejected: 0,
+        });
+        let memory_size = self.memory_size;
+        // removed again when dropped, also when the sort bails out with an error
+        let temp_dirs = TempDirs::create(&self.temp_dirs)?;
+        let mut summary = SortSummary::default();
+
+        check_inputs(&inputs)?;
+
+        let thread_pool = ThreadPoolBuilder::new()
+            .num_threads(self.threads)
+            .build()
+            .map_err(|error| RsortError::Config(format!("cannot start the sorting threads: {}", error)))?;
+
+        let mut output = Output {
+            sink: result_file,
+            format,
+            inputs: &inputs,
+            key: &keys[0].field,
+            keys_only: self.keys_only,
+            provenance: self.provenance,
+            records: 0,
+        };
+        if self.merge {
+            // the inputs are the runs, merged in several passes as the sorted runs are when there are
+            // more of them than can be open at once; an input is only opened for the pass merging it
+            let fan_in = max_fan_in(memory_size, self.merge_buffer, self.fan_in);
+            let input_sizes: Vec<usize> = inputs.iter()
+                .map(|path| path.metadata().map_or(0, |metadata| metadata.len() as usize))
+                .collect();
+            let mut plan = plan_merge_passes(&input_sizes, fan_in);
+            let final_merge = plan.pop().unwrap_or_default();
+            // the header of the first input that has one
+            let mut first_header: Option<(usize, Vec<u8>)> = None;
+            let mut open_run = |i: usize| -> Result<MergeRun> {
+                let path = match inputs.get(i) {
+                    Some(path) => path,
+                    None => return Ok(MergeRun::Merged(RunReader::open(temp_dirs.run_path(i))?))
                 };
+                let (run, header) = InputRun::open(path, i, open_input(path)?, keys, &extracted, format, encoding, to_utf8,
+                                                   &missing_keys, self.verify_sorted)?;
+                if let Some(header) = header {
+                    if first_header.as_ref().is_none_or(|(file, _)| i < *file) {
+                        first_header = Some((i, header));
+                    }
+                }
+                Ok(MergeRun::Input(run))
+            };
+            for (step, runs) in plan.into_iter().enumerate() {
+                let mut run_pool = runs.iter().map(|i| open_run(*i)).collect::<Result<Vec<MergeRun>>>()?;
+                let merged_path = temp_dirs.run_path(inputs.len() + step);
+                let mut merged = RunWriter::create(&merged_path)?;
+                merge_unique(&mut run_pool, keys, memory_size, unique, |rec| merged.write_record(&rec))?;
+                merged.finish()?;
+                summary.input_bytes += run_pool.iter().map(MergeRun::input_bytes).sum::<usize>();
+                // the merged temporary runs are not needed anymore, give their disk space back
+                for i in runs.iter().filter(|i| **i >= inputs.len()) {
+                    let path = temp_dirs.run_path(*i);
+                    remove_file(&path).map_err(|error| RsortError::io(&path, error))?;
+                }
+                summary.intermediate_merges += 1;
+            }
+
+            let mut run_pool = final_merge.iter().map(|i| open_run(*i)).collect::<Result<Vec<MergeRun>>>()?;
+            if let Some((_, header)) = first_header {
+                output.header(&header)?;
+            }
+            summary.runs = inputs.len();
+            merge_unique(&mut run_pool, keys, memory_size, unique, |rec| output.record(&rec))?;
+            summary.input_bytes += run_pool.iter().map(MergeRun::input_bytes).sum::<usize>();
+        } else {
+            let (run_sizes, read) = if self.replacement_selection {
+                let mut selection = ReplacementSelection::new(memory_size, keys, &temp_dirs);
+                let mut missing_keys = missing_keys.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                let read = read_records(&inputs, format, |record, origin| {
+                    let record = RawRecord::parse_raw_record(record, origin, &extracted, format, encoding, to_utf8);
+                    if missing_keys.keep(&record)? {
+                        selection.push(record)?;
+                    }
+                    Ok(())
+                })?;
+                (selection.finish()?, read)
+            } else {
+                // one chunk is read while the previous one is sorted and spilled, so each gets half the memory
+                let chunk_budget = (memory_size / 2).max(1);
+                thread::scope(|scope| -> Result<(Vec<usize>, InputRead)> {
+                    let mut spiller = ChunkSpiller {
+                        scope,
+                        thread_pool: &thread_pool,
+                        temp_dirs: &temp_dirs,
+                        keys,
+                        extracted: &extracted,
+                        unique,
+                        format,
+                        encoding,
+                        to_utf8,
+                        missing_keys: &missing_keys,
+                        spilling: None,
+                        run_sizes: Vec::new()
+                    };
+                    let mut internal_chunk_sort_pool: Vec<(Vec<u8>, RecordOrigin)> = Vec::new();
+                    let mut internal_chunk_sort_pool_cur_size = 0;
 
-                if left_node.record_end == true && right_node.record_end == true {
-                    internal_node[i].ptr = Some(terminator_pos);
-                } else if left_node.record_end == true && right_node.record_end == false {
-                    internal_node[i].ptr = Some(right_node_idx);
-                } else if left_node.record_end == false && right_node.record_end == true{
-                    internal_node[i].ptr = Some(left_node_idx);
-                } else {
-                    internal_node[i].ptr = Some(
-                        match left_node.record_key_value.cmp(&right_node.record_key_value) {
-                            Ordering::Equal => {
-                                match left_node.record_secondary_key_value.cmp(&right_node.record_secondary_key_value) {
-                                    Ordering::Equal => { left_node_idx }, // left node
-                                    Ordering::Greater => { right_node_idx }, // right node
-                                    Ordering::Less => { left_node_idx } // left node
-                                }
-                            },
-                            Ordering::Greater => {
-                                right_node_idx
-                            }, // right node
-                            Ordering::Less => {
-                                left_node_idx
-                            } // left node
+                    let read = read_records(&inputs, format, |record, origin| {
+                        // performing internal sort and write back to the file
+                        if internal_chunk_sort_pool_cur_size + record.len() >= chunk_budget &&
+                            !internal_chunk_sort_pool.is_empty() {
+                            spiller.spill(std::mem::take(&mut internal_chunk_sort_pool),
+                                          internal_chunk_sort_pool_cur_size)?;
+                            internal_chunk_sort_pool_cur_size = 0;
                         }
-                    );
+                        internal_chunk_sort_pool_cur_size += record.len();
+                        internal_chunk_sort_pool.push((record, origin));
+                        Ok(())
+                    })?;
+                    // write back the remain things
+                    if !internal_chunk_sort_pool.is_empty() {
+                        spiller.spill(internal_chunk_sort_pool, internal_chunk_sort_pool_cur_size)?;
+                    }
+                    Ok((spiller.finish()?, read))
+                })?
+            };
+            summary.runs = run_sizes.len();
+            summary.input_bytes = read.bytes;
+
+            // Performing the K-way external merge sort, in several passes when there are more runs
+            // than the memory allows to merge at once
+            let fan_in = max_fan_in(memory_size, self.merge_buffer, self.fan_in);
+            let mut plan = plan_merge_passes(&run_sizes, fan_in);
+            let final_merge = plan.pop().unwrap_or_default();
+            for (step, runs) in plan.into_iter().enumerate() {
+                let mut run_pool: Vec<RunReader> = Vec::with_capacity(runs.len());
+                for i in runs.iter() {
+                    run_pool.push(RunReader::open(temp_dirs.run_path(*i))?);
+                }
+                let merged_path = temp_dirs.run_path(run_sizes.len() + step);
+                let mut merged = RunWriter::create(&merged_path)?;
+                merge_unique(&mut run_pool, keys, memory_size, unique, |rec| merged.write_record(&rec))?;
+                merged.finish()?;
+                // the merged runs are not needed anymore, give their disk space back
+                for i in runs.iter() {
+                    let path = temp_dirs.run_path(*i);
+                    remove_file(&path).map_err(|error| RsortError::io(&path, error))?;
                 }
+                summary.intermediate_merges += 1;
+            }
+
+            let mut run_pool: Vec<RunReader> = Vec::with_capacity(final_merge.len());
+            for i in final_merge.iter() {
+                run_pool.push(RunReader::open(temp_dirs.run_path(*i))?);
+            }
+            if let Some(header) = read.header {
+                output.header(&header)?;
+            }
+            merge_unique(&mut run_pool, keys, memory_size, unique, |rec| output.record(&rec))?;
+        }
+        summary.records = output.finish()?;
+        let mut missing_keys = missing_keys.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        summary.duplicates = missing_keys.kept - summary.records;
+        summary.dropped = missing_keys.dropped;
+        summary.rejected = missing_keys.rejected;
+        if let Some(rejects) = missing_keys.rejects.as_mut() {
+            rejects.flush()?;
+        }
+
+        Ok(summary)
+    }
 
+    /// Checks that every input is sorted by the keys, without sorting it: each record is compared
+    /// with the one before it in the same input, as the sort compares them, and the check stops at
+    /// the first record out of order. The records the missing-key policies leave out are not
+    /// compared, as they are not sorted either. Nothing is written to the output.
+    pub fn check(mut self) -> Result<CheckSummary> {
+        self.default_keys()?;
+        let keys = self.keys.as_slice();
+        let gais = self.gais_format();
+        let format: &dyn RecordFormat = self.format.as_deref().unwrap_or(&gais);
+        let inputs = expand_inputs(&self.inputs)?;
+        let missing_keys = Mutex::new(MissingKeys {
+            keys,
+            format,
+            inputs: &inputs,
+            rejects: self.rejects.take(),
+            kept: 0,
+            dropped: 0,
+            rejected: 0,
+        });
+        check_inputs(&inputs)?;
+        let mut summary = CheckSummary::default();
 
+        'inputs: for (file, path) in inputs.iter().enumerate() {
+            let (mut run, _) = InputRun::open(path, file, open_input(path)?, keys, keys, format, self.encoding, self.to_utf8,
+                                              &missing_keys, false)?;
+            let mut previous: Option<RawRecord> = None;
+            while let Some(record) = run.next_record()? {
+                summary.records += 1;
+                if let Some(previous) = &previous {
+                    if compare_records(&record, previous, keys) == Ordering::Less {
+                        // the keys as written in the records, not their parsed form
+                        let fields = |record: &RawRecord| keys.iter()
+                            .map(|key| format.field(&record.raw_record, &key.field)
+                                .map(|value| String::from_utf8_lossy(&value).into_owned()))
+                            .collect();
+                        summary.disorder = Some(Disorder {
+                            path: path.clone(),
+                            previous: previous.origin.offset,
+                            offset: record.origin.offset,
+                            fields: keys.iter().map(|key| key.field.clone()).collect(),
+                            previous_keys: fields(previous),
+                            keys: fields(&record),
+                        });
+                        summary.input_bytes += run.origin.offset as usize;
+                        break 'inputs;
+                    }
+                }
+                previous = Some(record);
             }
+            summary.input_bytes += run.origin.offset as usize;
+        }
+        let mut missing_keys = missing_keys.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        summary.dropped = missing_keys.dropped;
+        summary.rejected = missing_keys.rejected;
+        if let Some(rejects) = missing_keys.rejects.as_mut() {
+            rejects.flush()?;
         }
-        i_tree_size /= 2;
+
+        Ok(summary)
     }
 
-    let max_ptr = match internal_node[1].ptr {
-        Some(idx) => { idx },
-        None => { panic!("Should not be None, if empty, each one should be inf rec."); }
-    };
+    /// Falls back on the default keys of a format other than GAIS when no key was given, keeping the
+    /// key types that were.
+    fn default_keys(&mut self) -> Result<()> {
+        let fields = match &self.format {
+            Some(format) if !self.keys_given => format.default_keys(),
+            _ => return Ok(())
+        };
+        if fields.is_empty() {
+            return Err(RsortError::Config("no sort key was given, and the record format has no default key".to_string()));
+        }
+        self.keys.truncate(fields.len());
+        for (i, field) in fields.iter().enumerate() {
+            match self.keys.get_mut(i) {
+                Some(key) => key.field = field.to_string(),
+                None => self.keys.push(KeySpec::new(field, KeyType::Text))
+            }
+        }
+        Ok(())
+    }
 
-    // popping up the value and reset the winner node to None
-    for i in 0..internal_node.len() {
-        if internal_node[i].ptr == Some(max_ptr) {
-            internal_node[i].ptr = None;
+    /// The GAIS format set up by the record delimiter and the body field, used when no other
+    /// format is given.
+    fn gais_format(&self) -> GaisFormat {
+        let format = GaisFormat::new(&self.rec_begin_pat);
+        match &self.body_field {
+            Some(body_field) => format.body_field(body_field),
+            None => format
         }
     }
+}
+
+/// Checks that every input exists up front, so a missing one fails before any work is done; the
+/// inputs are only opened as they are read, as there may be more of them than open files allowed.
+fn check_inputs(inputs: &[PathBuf]) -> Result<()> {
+    for filename in inputs.iter().filter(|filename| filename.as_os_str() != STDIN) {
+        filename.metadata().map_err(|error| RsortError::io(filename, error))?;
+    }
+    Ok(())
+}
 
-    max_ptr
+fn open_input(filename: &Path) -> Result<Box<dyn Read>> {
+    if filename.as_os_str() == STDIN {
+        Ok(Box::new(std::io::stdin()))
+    } else {
+        let file = File::open(filename).map_err(|error| RsortError::io(filename, error))?;
+        Ok(Box::new(file))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    /// A scratch directory for a test, removed when the test ends, also when it fails.
+    struct WorkDir(PathBuf);
+
+    impl WorkDir {
+        fn new(name: &str) -> WorkDir {
+            let dir = std::env::temp_dir().join(format!("rsort_{}_test_{}", name, std::process::id()));
+            create_dir_all(&dir).unwrap();
+            WorkDir(dir)
+        }
+    }
+
+    impl std::ops::Deref for WorkDir {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for WorkDir {
+        fn drop(&mut self) {
+            let _ = remove_dir_all(&self.0);
+        }
+    }
+
     #[test]
     fn key_value_fetching() {
         let test_str = "\
@@ -335,17 +1275,451 @@ mod tests {
 2016年06月17日 22:18
 記者黃庠棻／綜合報導 藝人修杰楷出道13年，2015年5月和大9歲的賈靜雯結婚，同年生下一女咘咘，夫妻倆常常會在臉書分享育兒生活，每次都會吸引大批網友迴響，前不久才在新北市政府服替代役的他近日放假，回到家中陪伴女兒，17日晚間又貼出一段訓練咘咘自己吃飯的影片，可愛的模樣造成粉絲熱烈討論。 ▲賈靜雯和修杰楷常會在臉書分享育兒生活。（圖／翻攝自修杰楷臉書） 修杰楷17日貼出一段咘咘吃飯的影片，表示自己開啟了課，要訓練女兒「吃東西就是要自己來」，只見咘咘坐在嬰兒用座椅，靠著自己的力量，抓著碗裡的食物往嘴塞，雖然動作還有些生澀、笨拙，但不用爸媽餵食，成功吃到東西的模樣也讓許多網友感到相當感動，紛紛大讚「咘咘會自己吃飯啦！」 ▲修杰楷貼出訓練咘咘自己吃飯的影片。（圖／翻攝自修杰楷臉書） 不僅如此，咘咘在連續兩次成功靠自身力量吃到飯之後，竟然伸出肉嘟嘟的雙手「拍手鼓掌」，就像自我鼓勵一樣，逗趣的舉動讓大批粉絲不僅笑成一片，也紛紛直呼「要被萌翻了啦！」該則影片也憑著她的高人氣，才貼出短短1小時就吸引超過4萬個人按讚。 ▲咘咘成功吃完飯後，竟然自己拍手鼓勵，可愛的模樣引起網友討論。（圖／翻攝自修杰楷臉書） ";
 
-        let kv_result = match key_value("@SiteCode:", &test_str) {
-            Ok(str) => str,
-            Err(str) => str
+        let kv_result = key_value("@SiteCode:", test_str.as_bytes(), None).unwrap();
+        assert_eq!(kv_result , b"LvYHeMlIgi");
+
+        let kv_result = key_value("@IP:", test_str.as_bytes(), None).unwrap();
+        assert_eq!(kv_result , b"219.85.79.132");
+
+        match key_value("@Author:", test_str.as_bytes(), None) {
+            Err(RsortError::MissingKey { key }) => assert_eq!(key, "@Author:"),
+            other => panic!("expected a missing key, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn key_value_matches_whole_fields() {
+        let record = b"@\n@Gais_REC:\n@Ref:http://a/@url:wrong\n@url:  http://a/1 \r\n@title:a title\n  going on\n\n  and on\n\
+                      @Size:89230\n@body:text\n@Author:in the body\n";
+
+        // only at the start of a line, and trimmed
+        assert_eq!(key_value("@url:", record, None).unwrap(), b"http://a/1");
+        // continued over the lines that do not open a field
+        assert_eq!(key_value("@title:", record, None).unwrap(), b"a title going on and on");
+        assert_eq!(key_value("@Size:", record, None).unwrap(), b"89230");
+
+        // the body is searched unless it is configured as such
+        assert_eq!(key_value("@Author:", record, None).unwrap(), b"in the body");
+        assert!(key_value("@Author:", record, Some("@body:")).is_err());
+        assert_eq!(key_value("@Size:", record, Some("@body:")).unwrap(), b"89230");
+        assert_eq!(key_value("@body:", record, Some("@body:")).unwrap(), b"text");
+    }
+
+    #[test]
+    fn memory_size_parsing() {
+        assert_eq!(parse_memory_size("65536").unwrap(), 65536);
+        assert_eq!(parse_memory_size("64K").unwrap(), 64 * 1024);
+        assert_eq!(parse_memory_size("512m").unwrap(), 512 * 1024 * 1024);
+        assert_eq!(parse_memory_size("16GB").unwrap(), 16 * 1024 * 1024 * 1024);
+        assert!(parse_memory_size("0").is_err());
+        assert!(parse_memory_size("12X").is_err());
+        assert!(parse_memory_size("").is_err());
+    }
+
+    #[test]
+    fn sorter_sorts_records() {
+        let work_dir = WorkDir::new("sorter");
+        let input = work_dir.join("input.rec");
+        let output = work_dir.join("output.rec");
+        let mut records = String::new();
+        for i in [5, 3, 9, 1, 7, 3].iter() {
+            records.push_str(&format!("@\n@Gais_REC:\n@url:http://site/{}\n@SiteCode:S{}\n@body:body\n", i, i));
+        }
+        std::fs::write(&input, &records).unwrap();
+
+        let summary = Sorter::new()
+            .input(&input)
+            .memory_size(128)
+            .temp_dir(work_dir.join("tmp"))
+            .output(File::create(&output).unwrap())
+            .sort()
+            .unwrap();
+
+        let sorted = std::fs::read_to_string(&output).unwrap();
+        let urls: Vec<&str> = sorted.lines().filter(|line| line.starts_with("@url:")).collect();
+        assert_eq!(urls, vec!["@url:http://site/1", "@url:http://site/3", "@url:http://site/3",
+                              "@url:http://site/5", "@url:http://site/7", "@url:http://site/9"]);
+        assert_eq!(sorted.len(), records.len());
+        assert_eq!(summary.records, 6);
+        assert_eq!(summary.input_bytes, records.len());
+        assert_eq!(std::fs::read_dir(work_dir.join("tmp")).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn sorter_keeps_equal_keys_in_input_order() {
+        let work_dir = WorkDir::new("stable");
+        let input = work_dir.join("input.rec");
+        let output = work_dir.join("output.rec");
+        let mut records = String::new();
+        for i in 0..60 {
+            records.push_str(&format!("@\n@Gais_REC:\n@url:http://site/{}\n@seq:{}\n", i * 7 % 3, i));
+        }
+        std::fs::write(&input, &records).unwrap();
+        let sort = |fan_in: Option<usize>| {
+            let sorter = Sorter::new()
+                .input(&input)
+                .keys(vec!["@url:".parse().unwrap()])
+                .memory_size(256)
+                .merge_buffer(1)
+                .temp_dir(work_dir.join("tmp"))
+                .output(File::create(&output).unwrap());
+            let summary = match fan_in {
+                Some(fan_in) => sorter.fan_in(fan_in).sort(),
+                None => sorter.sort()
+            }.unwrap();
+            (summary.intermediate_merges, std::fs::read_to_string(&output).unwrap())
+        };
+
+        let (merges, single_pass) = sort(None);
+        assert_eq!(merges, 0);
+        let (merges, multi_pass) = sort(Some(2));
+        assert!(merges > 0);
+        assert_eq!(single_pass, multi_pass);
+        let seqs: Vec<usize> = single_pass.lines()
+            .filter_map(|line| line.strip_prefix("@seq:"))
+            .map(|seq| seq.parse().unwrap())
+            .collect();
+        for url in 0..3 {
+            let group: Vec<usize> = seqs.iter().cloned().filter(|seq| seq * 7 % 3 == url).collect();
+            assert!(group.windows(2).all(|pair| pair[0] < pair[1]));
+        }
+    }
+
+    #[test]
+    fn sorter_keeps_raw_bytes() {
+        let work_dir = WorkDir::new("bytes");
+        let input = work_dir.join("input.rec");
+        let output = work_dir.join("output.rec");
+        // Big5 titles, a broken UTF-8 sequence and a CRLF line ending
+        let records: [&[u8]; 3] = [
+            b"@\n@Gais_REC:\n@url:http://b\n@title:\xad\xd7\xa9\xa3\r\n",
+            b"@\n@Gais_REC:\n@url:http://c\n@title:\xe4\xbf\n",
+            b"@\n@Gais_REC:\n@url:http://a\n@title:\xa5\x78\xc6\x57\n",
+        ];
+        std::fs::write(&input, records.concat()).unwrap();
+
+        Sorter::new()
+            .input(&input)
+            .memory_size(64)
+            .temp_dir(work_dir.join("tmp"))
+            .output(File::create(&output).unwrap())
+            .sort()
+            .unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), [records[2], records[0], records[1]].concat());
+
+        // text keys compare as bytes
+        Sorter::new()
+            .input(&input)
+            .keys(vec!["@title:".parse().unwrap()])
+            .temp_dir(work_dir.join("tmp"))
+            .output(File::create(&output).unwrap())
+            .sort()
+            .unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), [records[2], records[0], records[1]].concat());
+    }
+
+    #[test]
+    fn sorter_decodes_input_encoding() {
+        let work_dir = WorkDir::new("encoding");
+        let input = work_dir.join("input.rec");
+        let output = work_dir.join("output.rec");
+        // 文 and 中 sort the other way round in GBK than in Unicode
+        std::fs::write(&input, b"@\n@Gais_REC:\n@Lang:gbk\n@title:\xce\xc4\n@\n@Gais_REC:\n@Lang:big5\n@title:\xa4\xa4\n").unwrap();
+
+        Sorter::new()
+            .input(&input)
+            .keys(vec!["@title:".parse().unwrap()])
+            .input_encoding(InputEncoding::Auto)
+            .output_utf8(true)
+            .temp_dir(work_dir.join("tmp"))
+            .output(File::create(&output).unwrap())
+            .sort()
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&output).unwrap(),
+                   "@\n@Gais_REC:\n@Lang:utf-8\n@title:中\n@\n@Gais_REC:\n@Lang:utf-8\n@title:文\n");
+    }
+
+    #[test]
+    fn sorter_applies_missing_key_policies() {
+        let work_dir = WorkDir::new("missing");
+        let input = work_dir.join("input.rec");
+        let records = "@\n@Gais_REC:\n@url:b\n@Size:2\n@\n@Gais_REC:\n@url:c\n@\n@Gais_REC:\n@url:a\n@Size:1\n";
+        std::fs::write(&input, records).unwrap();
+        let sort = |spec: &str| {
+            let output = work_dir.join("output.rec");
+            let rejects = work_dir.join("rejects.rec");
+            let summary = Sorter::new()
+                .input(&input)
+                .keys(vec![spec.parse().unwrap()])
+                .memory_size(64)
+                .temp_dir(work_dir.join("tmp"))
+                .output(File::create(&output).unwrap())
+                .rejects(File::create(&rejects).unwrap())
+                .sort();
+            let urls: String = std::fs::read_to_string(&output).unwrap().lines()
+                .filter_map(|line| line.strip_prefix("@url:")).collect();
+            (summary, urls, std::fs::read_to_string(&rejects).unwrap())
+        };
+
+        assert_eq!(sort("@Size:,integer,desc").1, "cba");
+        assert_eq!(sort("@Size:,integer,desc,missing=last").1, "bac");
+        let (summary, urls, _) = sort("@Size:,integer,missing=drop");
+        assert_eq!((summary.unwrap().dropped, urls.as_str()), (1, "ab"));
+        let (summary, urls, rejects) = sort("@Size:,integer,missing=reject");
+        assert_eq!((summary.unwrap().rejected, urls.as_str()), (1, "ab"));
+        assert_eq!(rejects, "@\n@Gais_REC:\n@url:c\n");
+        match sort("@Size:,integer,missing=abort").0 {
+            Err(RsortError::RecordWithoutKey { key, offset, .. }) => assert_eq!((key.as_str(), offset), ("@Size:", 28)),
+            other => panic!("expected a record without its key, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn sorter_sorts_by_several_keys() {
+        let work_dir = WorkDir::new("keys");
+        let input = work_dir.join("input.rec");
+        let output = work_dir.join("output.rec");
+        let mut records = String::new();
+        for (site, size, title) in [("B", 7, "x"), ("A", 10, "b"), ("A", 9, "c"), ("A", 10, "A"), ("B", 70, "y")].iter() {
+            records.push_str(&format!("@\n@Gais_REC:\n@SiteCode:{}\n@Size:{}\n@title:{}\n", site, size, title));
+        }
+        std::fs::write(&input, &records).unwrap();
+
+        Sorter::new()
+            .input(&input)
+            .keys(vec!["@SiteCode:".parse().unwrap(), "@Size:,integer,desc".parse().unwrap(),
+                       "@title:,ignore-case".parse().unwrap()])
+            .memory_size(64)
+            .temp_dir(work_dir.join("tmp"))
+            .output(File::create(&output).unwrap())
+            .sort()
+            .unwrap();
+
+        let sorted = std::fs::read_to_string(&output).unwrap();
+        let titles: Vec<&str> = sorted.lines().filter(|line| line.starts_with("@title:")).collect();
+        assert_eq!(titles, vec!["@title:A", "@title:b", "@title:c", "@title:y", "@title:x"]);
+    }
+
+    #[test]
+    fn sorter_keeps_unique_records() {
+        let work_dir = WorkDir::new("unique");
+        let input = work_dir.join("input.rec");
+        let output = work_dir.join("output.rec");
+        let mut records = String::new();
+        for (url, fetched) in [("b", 3), ("a", 1), ("b", 9), ("c", 2), ("a", 5), ("b", 4), ("a", 2)].iter() {
+            records.push_str(&format!("@\n@Gais_REC:\n@url:{}\n@Fetchtime:2017/01/0{} 00:00:00\n", url, fetched));
+        }
+        std::fs::write(&input, &records).unwrap();
+        // small runs, merged two at a time, so duplicates meet within runs and in every merge
+        let unique = |survivor: &str, replacement_selection: bool| {
+            let summary = Sorter::new()
+                .input(&input)
+                .keys(vec!["@url:".parse().unwrap()])
+                .unique(survivor.parse().unwrap())
+                .memory_size(150)
+                .fan_in(2)
+                .replacement_selection(replacement_selection)
+                .temp_dir(work_dir.join("tmp"))
+                .output(File::create(&output).unwrap())
+                .sort()
+                .unwrap();
+            assert_eq!((summary.records, summary.duplicates), (3, 4));
+            let sorted = std::fs::read_to_string(&output).unwrap();
+            sorted.lines().filter(|line| line.starts_with("@Fetchtime:")).map(|line| line[20..21].to_string()).collect::<String>()
         };
-        assert_eq!(kv_result , "LvYHeMlIgi".to_string());
 
-        let kv_result = match key_value("@IP:", &test_str) {
-            Ok(str) => str,
-            Err(str) => str
+        assert_eq!(unique("first", false), "132");
+        assert_eq!(unique("last", false), "242");
+        assert_eq!(unique("max:@Fetchtime:,time", false), "592");
+        assert_eq!(unique("min:@Fetchtime:,time", true), "132");
+    }
+
+    #[test]
+    fn sorter_merges_many_inputs_with_their_sources() {
+        let work_dir = WorkDir::new("inputs");
+        create_dir_all(work_dir.join("crawl/more")).unwrap();
+        let output = work_dir.join("output.rec");
+        std::fs::write(work_dir.join("crawl/1.rec"), "@\n@Gais_REC:\n@url:c\n@\n@Gais_REC:\n@url:a\n").unwrap();
+        std::fs::write(work_dir.join("crawl/2.rec"), "@\n@Gais_REC:\n@url:b\n").unwrap();
+        std::fs::write(work_dir.join("crawl/more/3.rec"), "@\n@Gais_REC:\n@url:d\n").unwrap();
+
+        let summary = Sorter::new()
+            .input(work_dir.join("crawl/*.rec"))
+            .input(work_dir.join("crawl/more"))
+            .keys(vec!["@url:".parse().unwrap()])
+            .provenance(true)
+            .temp_dir(work_dir.join("tmp"))
+            .output(File::create(&output).unwrap())
+            .sort()
+            .unwrap();
+        let crawl = work_dir.join("crawl");
+        assert_eq!(summary.records, 4);
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), format!(
+            "@\n@Gais_REC:\n@Source:{0}/1.rec:20\n@url:a\n@\n@Gais_REC:\n@Source:{0}/2.rec:0\n@url:b\n\
+             @\n@Gais_REC:\n@Source:{0}/1.rec:0\n@url:c\n@\n@Gais_REC:\n@Source:{0}/more/3.rec:0\n@url:d\n",
+            crawl.display()));
+    }
+
+    #[test]
+    fn sorter_merges_sorted_inputs() {
+        let work_dir = WorkDir::new("merge");
+        let inputs = [work_dir.join("a.csv"), work_dir.join("b.csv")];
+        let output = work_dir.join("output.csv");
+        std::fs::write(&inputs[0], "n,v\n1,a\n4,d\n6,f\n").unwrap();
+        std::fs::write(&inputs[1], "n,v\n2,b\n3,c\n5,e\n").unwrap();
+        let merge = |verify: bool| Sorter::new()
+            .input(&inputs[0])
+            .input(&inputs[1])
+            .format(CsvFormat::csv())
+            .keys(vec!["n,integer".parse().unwrap()])
+            .merge(true)
+            .verify_sorted(verify)
+            .temp_dir(work_dir.join("tmp"))
+            .output(File::create(&output).unwrap())
+            .sort();
+
+        let summary = merge(true).unwrap();
+        assert_eq!((summary.records, summary.runs, summary.input_bytes), (6, 2, 32));
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "n,v\n1,a\n2,b\n3,c\n4,d\n5,e\n6,f\n");
+
+        std::fs::write(&inputs[1], "n,v\n2,b\n5,e\n3,c\n").unwrap();
+        match merge(true) {
+            Err(RsortError::Unsorted { path, previous, offset }) => assert_eq!((path, previous, offset), (inputs[1].clone(), 8, 12)),
+            other => panic!("expected an unsorted input, got {:?}", other)
+        }
+        // without verifying, the order is taken on trust
+        assert_eq!(merge(false).unwrap().records, 6);
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "n,v\n1,a\n2,b\n4,d\n5,e\n3,c\n6,f\n");
+    }
+
+    #[test]
+    fn sorter_merges_more_inputs_than_the_fan_in() {
+        let work_dir = WorkDir::new("merge-passes");
+        let output = work_dir.join("output.csv");
+        create_dir_all(work_dir.join("sorted")).unwrap();
+        let mut expected = String::from("n,v\n");
+        for i in 0..5 {
+            let rows: String = (0..4).map(|j| format!("{},{}\n", j * 5 + i, i)).collect();
+            std::fs::write(work_dir.join(format!("sorted/{}.csv", i)), format!("n,v\n{}", rows)).unwrap();
+        }
+        for n in 0..20 {
+            expected.push_str(&format!("{},{}\n", n, n % 5));
+        }
+        let input_bytes: usize = (0..5).map(|i| work_dir.join(format!("sorted/{}.csv", i)).metadata().unwrap().len() as usize).sum();
+
+        let summary = Sorter::new()
+            .input(work_dir.join("sorted"))
+            .format(CsvFormat::csv())
+            .keys(vec!["n,integer".parse().unwrap()])
+            .merge(true)
+            .verify_sorted(true)
+            .fan_in(2)
+            .temp_dir(work_dir.join("tmp"))
+            .output(File::create(&output).unwrap())
+            .sort()
+            .unwrap();
+        assert_eq!((summary.records, summary.runs, summary.intermediate_merges, summary.input_bytes), (20, 5, 3, input_bytes));
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), expected);
+    }
+
+    #[test]
+    fn sorter_checks_order() {
+        let work_dir = WorkDir::new("check");
+        let input = work_dir.join("input.rec");
+        let check = || Sorter::new().input(&input).check().unwrap();
+
+        std::fs::write(&input, "@Gais_REC:\n@url:a\n@Gais_REC:\n@url:b\n@SiteCode:1\n@Gais_REC:\n@url:b\n@SiteCode:2\n").unwrap();
+        let summary = check();
+        assert_eq!((summary.records, summary.input_bytes, summary.disorder), (3, 78, None));
+
+        std::fs::write(&input, "@Gais_REC:\n@url:a\n@Gais_REC:\n@url:c\n@SiteCode:1\n@Gais_REC:\n@url:b\n@Gais_REC:\n@url:d\n").unwrap();
+        let disorder = check().disorder.unwrap();
+        assert_eq!((disorder.previous, disorder.offset), (18, 48));
+        assert_eq!(disorder.previous_keys, vec![Some("c".to_string()), Some("1".to_string())]);
+        assert_eq!(disorder.keys, vec![Some("b".to_string()), None]);
+        assert_eq!(disorder.to_string(), format!("{}: the record at byte 48 sorts before the one at byte 18\n  \
+                                                  byte 18: @url: \"c\", @SiteCode: \"1\"\n  \
+                                                  byte 48: @url: \"b\", @SiteCode: missing", input.display()));
+    }
+
+    #[test]
+    fn sorter_reads_other_formats() {
+        let work_dir = WorkDir::new("format");
+        let input = work_dir.join("input.txt");
+        let output = work_dir.join("output.txt");
+        let sort = |sorter: Sorter, keys: &[&str]| {
+            sorter
+                .input(&input)
+                .keys(keys.iter().map(|key| key.parse().unwrap()).collect())
+                .memory_size(16)
+                .temp_dir(work_dir.join("tmp"))
+                .output(File::create(&output).unwrap())
+                .sort()
+                .unwrap();
+            std::fs::read(&output).unwrap()
         };
-        assert_eq!(kv_result , "219.85.79.132".to_string());
+
+        // the last line gets the newline it was missing
+        std::fs::write(&input, "pear 3\napple 10\nfig 7").unwrap();
+        assert_eq!(sort(Sorter::new().format(DelimitedFormat::lines()), &["0"]), b"apple 10\nfig 7\npear 3\n");
+        assert_eq!(sort(Sorter::new().format(DelimitedFormat::lines()), &["2,integer"]), b"pear 3\nfig 7\napple 10\n");
+        // without keys the records are sorted whole, not by GAIS fields they do not have
+        assert_eq!(sort(Sorter::new().format(DelimitedFormat::lines()), &[]), b"apple 10\nfig 7\npear 3\n");
+        assert_eq!(sort(Sorter::new().format(DelimitedFormat::lines()).key_type(KeyType::Version), &[]),
+                   b"apple 10\nfig 7\npear 3\n");
+
+        std::fs::write(&input, "b\nsecond line\0a\0").unwrap();
+        assert_eq!(sort(Sorter::new().format(DelimitedFormat::nul()), &["0"]), b"a\0b\nsecond line\0");
+
+        std::fs::write(&input, "== 2\nSize: 5\n== 1\nSize: 10\n").unwrap();
+        let regex = || Sorter::new().format(RegexFormat::new("^== ").unwrap());
+        assert_eq!(sort(regex(), &["Size:,integer"]), b"== 2\nSize: 5\n== 1\nSize: 10\n");
+        assert_eq!(sort(regex(), &["2"]), b"== 1\nSize: 10\n== 2\nSize: 5\n");
+
+        std::fs::write(&input, "{\"meta\":{\"t\":\"b\"}}\n{\"meta\":{\"t\":2}}\n{\"meta\":{}}\n{\"meta\":{\"t\":null}}\n").unwrap();
+        assert_eq!(sort(Sorter::new().format(JsonFormat), &[".meta.t,json,missing=last"]),
+                   b"{\"meta\":{\"t\":null}}\n{\"meta\":{\"t\":2}}\n{\"meta\":{\"t\":\"b\"}}\n{\"meta\":{}}\n");
+        assert_eq!(sort(Sorter::new().format(JsonFormat), &[]),
+                   b"{\"meta\":{\"t\":\"b\"}}\n{\"meta\":{\"t\":2}}\n{\"meta\":{\"t\":null}}\n{\"meta\":{}}\n");
+
+        std::fs::write(&input, "n,v\n2,b\n10,a\n").unwrap();
+        assert_eq!(sort(Sorter::new().format(CsvFormat::csv()), &[]), b"n,v\n10,a\n2,b\n");
+
+        // a format without default keys needs them given
+        struct Unkeyed(DelimitedFormat);
+        impl RecordFormat for Unkeyed {
+            fn split<'a>(&'a self, reader: Box<dyn BufRead + 'a>) -> Box<dyn Iterator<Item = std::io::Result<Vec<u8>>> + 'a> {
+                self.0.split(reader)
+            }
+            fn field(&self, _record: &[u8], _name: &str) -> Option<Vec<u8>> {
+                None
+            }
+            fn serialize(&self, record: &[u8], writer: &mut dyn Write) -> std::io::Result<()> {
+                writer.write_all(record)
+            }
+        }
+        assert!(matches!(Sorter::new().input(&input).format(Unkeyed(DelimitedFormat::lines())).output(std::io::sink()).sort(), Err(RsortError::Config(_))));
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn sorter_sorts_csv_under_its_header() {
+        let work_dir = WorkDir::new("csv");
+        let inputs = [work_dir.join("a.csv"), work_dir.join("b.csv")];
+        let output = work_dir.join("output.csv");
+        std::fs::write(&inputs[0], "name,size\n\"pear\nand fig\",3\napple,10\n").unwrap();
+        std::fs::write(&inputs[1], "name,size\n\"kiwi, gold\",7\n").unwrap();
+
+        Sorter::new()
+            .input(&inputs[0])
+            .input(&inputs[1])
+            .format(CsvFormat::csv())
+            .keys(vec!["size,integer".parse().unwrap()])
+            .memory_size(16)
+            .temp_dir(work_dir.join("tmp"))
+            .output(File::create(&output).unwrap())
+            .sort()
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&output).unwrap(),
+                   "name,size\n\"pear\nand fig\",3\n\"kiwi, gold\",7\napple,10\n");
+    }
+}
@@ -1,233 +1,341 @@
-use std::fs::{File, OpenOptions, remove_dir_all};
-use std::io::{BufRead, BufReader, Write};
-use std::collections::VecDeque;
-use rsort::{key_value, fill_the_queue, winner_tree_by_idx, internal_pool_sort, InternalNode, RawRecord, Queue};
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::OnceLock;
+use clap::{App, Arg, ArgMatches, ErrorKind};
+use rsort::{parse_memory_size, remove_temp_dirs, CsvFormat, DelimitedFormat, InputEncoding, JsonFormat, KeySpec, KeyType,
+            RegexFormat, Result, RsortError, SortSummary, Sorter, Survivor};
+
+/// The file the sorted result is written to before it is renamed over the output.
+static PARTIAL_OUTPUT: OnceLock<PathBuf> = OnceLock::new();
 
 fn main() {
-    //find . -name 'rec_*' | xargs rm
-    let filename = String::from("ettoday.rec");
-    let rec_begin_pat = String::from("@Gais_REC:\n");
-    let primary_key_pat = String::from("@url:");
-    let secondary_key_pat = String::from("@SiteCode:");
-
-    let file = match File::open(filename) {
-        Ok(file) => file,
-        Err(error) => {
-            panic!("Something when wrong while opening the file. Details: {:?}", error);
-        }
-    };
-    let file_meta = match file.metadata() {
-        Ok(file_meta) => file_meta,
-        Err(error) => {
-            panic!("Cannot read the metadata from the file. Details: {:?}", error);
-        }
-    };
-
-    // The initial settings
-    // ---------------------M------K------B---
-    let memory_size: usize = 512 * 1024 * 1024; // 16 GB
-    let total_size: usize = file_meta.len() as usize; // this is the total file size
-    let chunk_size: usize = match total_size / memory_size == 0 {
-        true => 1,
-        false => 2.0f64.powf(((total_size as f64 / memory_size as f64).log2()).ceil()) as usize
-    };
-    // chunk_size, or called K-way
-    // the chuck_size must be the power of 2; the formula is 2 ^ ceil of lg N.
-
-    let queue_size: usize =  (memory_size as f64 / chunk_size as f64).ceil() as usize;
-    // there are 2-way to pick up the queue_size, one is mem_size/chunk_size,
-    // but if the total data cannot distribute evenly, we may calc the total rec size and div by chunk_size
-
-    let mut internal_chunk_sort_pool: Vec<RawRecord> = Vec::with_capacity(memory_size);
-    let mut internal_chunk_sort_pool_cur_size = 0;
-    let mut internal_chunk_count = 0;
-
-    println!("{} {} {} {}",  memory_size, total_size, chunk_size, queue_size);
-
-    // To parsing the record, using BufReader
-    let mut reader = BufReader::new(file);
-    let mut line: Vec<u8> = Vec::new();
-    let mut record_tmp: String = String::new();
-
-    if true {
-        while match reader.read_until(0xA, &mut line) {
-            Ok(read_size) => read_size > 0,
-            Err(error) => {
-                panic!("Something error while reading line. Details: {:?}", error);
+    let matches = App::new("rsort")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("An external merge sort for GAIS records")
+        .arg(Arg::with_name("input")
+            .value_name("INPUT")
+            .help("The record file(s) to sort, - for the standard input; a directory stands for every file \
+                   under it and a glob pattern such as 'crawl/*.rec' for every file it matches")
+            .required(true)
+            .multiple(true))
+        .arg(Arg::with_name("output")
+            .short("o")
+            .long("output")
+            .value_name("FILE")
+            .help("Where to write the sorted result, - for the standard output; it may be one of the inputs, replaced once \
+                   the sort is done")
+            .required_unless("check"))
+        .arg(Arg::with_name("format")
+            .short("F")
+            .long("format")
+            .value_name("FORMAT")
+            .help("How the input is split into records: gais, lines (one record per line), nul (NUL-delimited \
+                   records), regex:PATTERN (multi-line records starting at the lines PATTERN matches), csv, tsv or \
+                   jsonl (one JSON document per line, keyed by paths such as .meta.fetch_time)")
+            .default_value("gais")
+            .validator(|format| check_format(&format)))
+        .arg(Arg::with_name("field-delimiter")
+            .long("field-delimiter")
+            .value_name("CHAR")
+            .help("The field delimiter of the csv and tsv formats, a single byte or \\t for a tab")
+            .validator(|delimiter| field_delimiter(&delimiter).map(|_| ())))
+        .arg(Arg::with_name("no-header")
+            .long("no-header")
+            .help("The csv and tsv inputs have no header row; their columns are only named by index"))
+        .arg(Arg::with_name("record-begin")
+            .short("b")
+            .long("record-begin")
+            .value_name("PATTERN")
+            .help("The line pattern marking the beginning of a GAIS record")
+            .default_value("@Gais_REC:"))
+        .arg(Arg::with_name("body-field")
+            .long("body-field")
+            .value_name("PATTERN")
+            .help("The field opening the body of a GAIS record, such as @body:; the keys are only looked for before it"))
+        .arg(Arg::with_name("input-encoding")
+            .long("input-encoding")
+            .value_name("ENCODING")
+            .help("How the records are decoded before their keys are extracted: raw, auto to follow the @Lang: \
+                   field of each record, or an encoding such as big5, gbk or utf-8")
+            .default_value("raw")
+            .validator(|label| label.parse::<InputEncoding>().map(|_| ()).map_err(|error| error.to_string())))
+        .arg(Arg::with_name("provenance")
+            .long("provenance")
+            .help("Note in every record where it was read from, as path:offset: a @Source: field for GAIS \
+                   records, a source column for csv and tsv, a _source member for jsonl, a tab-separated \
+                   prefix otherwise"))
+        .arg(Arg::with_name("output-utf8")
+            .long("output-utf8")
+            .help("Write the records decoded to UTF-8 instead of in their input encoding, rewriting their \
+                   @Lang: field to utf-8; needs an --input-encoding other than raw"))
+        .arg(Arg::with_name("key")
+            .short("k")
+            .long("key")
+            .value_name("PATTERN")
+            .help("The field pattern of the primary sort key; without any key, the records of other formats \
+                   than gais are sorted whole")
+            .default_value("@url:"))
+        .arg(Arg::with_name("secondary-key")
+            .short("s")
+            .long("secondary-key")
+            .value_name("PATTERN")
+            .help("The field pattern of the secondary sort key")
+            .default_value("@SiteCode:"))
+        .arg(Arg::with_name("key-type")
+            .long("key-type")
+            .value_name("TYPE")
+            .help("How the primary key is ordered: text, integer, float, size, time, time:FORMAT, version or json")
+            .default_value("text")
+            .validator(|key_type| key_type.parse::<KeyType>().map(|_| ()).map_err(|error| error.to_string())))
+        .arg(Arg::with_name("secondary-key-type")
+            .long("secondary-key-type")
+            .value_name("TYPE")
+            .help("How the secondary key is ordered, like --key-type")
+            .default_value("text")
+            .validator(|key_type| key_type.parse::<KeyType>().map(|_| ()).map_err(|error| error.to_string())))
+        .arg(Arg::with_name("sort-key")
+            .short("K")
+            .long("sort-key")
+            .value_name("SPEC")
+            .help("A sort key as FIELD[,TYPE][,OPTION]..., such as @Fetchtime:,time,desc or \
+                   @title:,nfkc,collate=pinyin; the options are asc or desc, ignore-case, nfc or nfkc, fold-width, \
+                   collate=COLLATION (binary, unicode, pinyin, stroke or zhuyin) and missing=POLICY. Repeat it for \
+                   more keys, compared in order. Replaces --key and \
+                   --secondary-key. A record without the key sorts first, or as the policy says: first, last, \
+                   drop, reject or abort")
+            .multiple(true)
+            .number_of_values(1)
+            .validator(|spec| spec.parse::<KeySpec>().map(|_| ()).map_err(|error| error.to_string())))
+        .arg(Arg::with_name("reject-file")
+            .long("reject-file")
+            .value_name("FILE")
+            .help("Where to write the records missing a key with the reject policy"))
+        .arg(Arg::with_name("unique")
+            .short("u")
+            .long("unique")
+            .help("Keep only one record of the records with the same keys, the first one in the input unless \
+                   --survivor says otherwise"))
+        .arg(Arg::with_name("survivor")
+            .long("survivor")
+            .value_name("POLICY")
+            .requires("unique")
+            .help("Which record --unique keeps: first, last, or max:KEY or min:KEY for the one with the greatest \
+                   or smallest value of another key, such as max:@Fetchtime:,time [default: first]")
+            .validator(|survivor| survivor.parse::<Survivor>().map(|_| ()).map_err(|error| error.to_string())))
+        .arg(Arg::with_name("memory")
+            .short("S")
+            .long("memory")
+            .value_name("SIZE")
+            .help("The memory budget for run generation, with an optional K, M or G suffix")
+            .default_value("512M")
+            .validator(|size| parse_memory_size(&size).map(|_| ()).map_err(|error| error.to_string())))
+        .arg(Arg::with_name("replacement-selection")
+            .long("replacement-selection")
+            .help("Generate runs by replacement selection, giving longer runs on partially sorted input"))
+        .arg(Arg::with_name("merge")
+            .short("m")
+            .long("merge")
+            .help("Merge inputs that are each sorted already, without sorting them again"))
+        .arg(Arg::with_name("verify")
+            .long("verify")
+            .requires("merge")
+            .help("With --merge, fail at the first record of an input that is out of order"))
+        .arg(Arg::with_name("check")
+            .short("c")
+            .long("check")
+            .conflicts_with_all(&["output", "merge"])
+            .help("Only check that every input is sorted by the keys, exiting with 1 and printing the first \
+                   pair of records out of order, with their byte offsets and keys, when one is not"))
+        .arg(Arg::with_name("fan-in")
+            .long("fan-in")
+            .value_name("N")
+            .help("The most runs merged at once; more runs are merged in several passes")
+            .validator(|n| match n.parse::<usize>() {
+                Ok(n) if n >= 2 => Ok(()),
+                _ => Err(format!("the fan-in must be a number of at least 2: {}", n))
+            }))
+        .arg(Arg::with_name("merge-buffer")
+            .long("merge-buffer")
+            .value_name("SIZE")
+            .help("The smallest read-ahead buffer of a run during a merge, bounding the fan-in to the memory budget")
+            .default_value("1M")
+            .validator(|size| parse_memory_size(&size).map(|_| ()).map_err(|error| error.to_string())))
+        .arg(Arg::with_name("threads")
+            .long("threads")
+            .value_name("N")
+            .help("The number of threads extracting keys and sorting runs, 0 for one per CPU")
+            .default_value("0")
+            .validator(|n| n.parse::<usize>().map(|_| ()).map_err(|_| format!("invalid number of threads: {}", n))))
+        .arg(Arg::with_name("temp-dir")
+            .short("T")
+            .long("temp-dir")
+            .value_name("DIR")
+            .help("A directory holding the temporary sorted runs; repeat to spread the runs over several disks \
+                   [default: $TMPDIR or /tmp]")
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("keys-only")
+            .long("keys-only")
+            .help("Write only the primary key of each sorted record instead of the whole record"))
+        .get_matches_safe()
+        .unwrap_or_else(|error| match error.kind {
+            ErrorKind::HelpDisplayed | ErrorKind::VersionDisplayed => error.exit(),
+            // exit 1 is left to --check finding an input out of order
+            _ => {
+                eprintln!("{}", error.message);
+                process::exit(2);
             }
-        } {
-            let repaired_line = String::from_utf8_lossy(&line);
-            if repaired_line.contains(&rec_begin_pat) {
-                // write back the record
-                // 1. check the record_tmp len
-                if record_tmp.len() > 0 {
-                    if internal_chunk_sort_pool_cur_size + record_tmp.len() < memory_size {
-                        internal_chunk_sort_pool.push(RawRecord {
-                            raw_record: record_tmp.clone(),
-                            record_size: record_tmp.len(),
-                            record_key_value: Some(match key_value(
-                                &primary_key_pat.as_str(),
-                                &record_tmp.as_str()
-                            ) {
-                                Ok(str) => str,
-                                Err(str) => str
-                            }),
-                            record_secondary_key_value: Some(match key_value(
-                                &secondary_key_pat.as_str(),
-                                &record_tmp.as_str()
-                            ) {
-                                Ok(str) => str,
-                                Err(str) => str
-                            },
-                            ),
-                            record_end: false
-                        });
-                        internal_chunk_sort_pool_cur_size += record_tmp.len()
-                    } else { // performing internal sort and write back to the file
-                        internal_pool_sort(&mut internal_chunk_sort_pool, internal_chunk_count);
-                        internal_chunk_sort_pool.clear();
-                        internal_chunk_sort_pool_cur_size = 0;
-                        internal_chunk_count += 1;
-                    }
-
-                    record_tmp.clear();
+        });
 
-                }
-            }
-            record_tmp.push_str(&repaired_line);
-            line.clear();
+    // an interrupted sort still removes its temporary runs and its partial output
+    let handled = ctrlc::set_handler(|| {
+        remove_temp_dirs();
+        if let Some(partial) = PARTIAL_OUTPUT.get() {
+            let _ = fs::remove_file(partial);
         }
-        // write back the remain things
-        internal_pool_sort(&mut internal_chunk_sort_pool, internal_chunk_count);
-        internal_chunk_sort_pool.clear();
+        process::exit(130);
+    });
+    if let Err(error) = handled {
+        eprintln!("rsort: cannot install the Ctrl-C handler: {}", error);
     }
 
-    // Performing the K-way external merge sort
-    // initialising the K-way buffer
-    // we confirm that all the queues have the data
-    // ready to do K-way external merge-sort
-    // Strategies -- the loop:
-    // 1. pick up the record from top of queues
-    // P.S. because loser(winner) tree is completed binary tree; thus, we might impl by array
-    // 2. pick up the min/max which was generated by the tournament tree.
-    // 3. check each queue whether has been already empty.
-
-
-    let record_queue = Queue {
-        queue: VecDeque::with_capacity(queue_size),
-        current_size: 0,
-        record_cnt: 0,
-        end_of_record: false
-    };
-//    let mut queue_pool: Arc<Vec<Box<Queue>>> = Arc::new(vec![Box::new(record_queue); chunk_size]);
-    let mut queue_pool: Vec<Box<Queue>> = vec![Box::new(record_queue); chunk_size];
-
-    // Compute the total loser tree elements
-    let i_ele_size = chunk_size;
-    let e_ele_size = chunk_size;
-
-    // Initialising the winner tree
-
-    let mut external_node: Vec<Box<Option<RawRecord>>> =vec![Box::new(None); e_ele_size];
-    external_node.push(Box::new(Some(RawRecord::new_raw_record()))); // set a terminator
-
-    let mut internal_node = vec![InternalNode::new_non_leaf_inode(); i_ele_size / 2];
-    for _i in 0..i_ele_size / 2 {
-        internal_node.push(InternalNode::new_leaf_inode())
-    }
-
-    let mut rec_cnt = 0;
-
-
-    println!("Starting to sort");
-    let mut result_file = match OpenOptions::new()
-        .write(true)
-        .create(true)
-        .open(format!("/tmp/result_rec_url")) {
-        Ok(file) => file,
+    match run(&matches) {
+        Ok(true) => (),
+        Ok(false) => process::exit(1),
         Err(error) => {
-            panic!("Something error while creating temporary result record file. Details: {:?}", error);
+            eprintln!("rsort: {}", error);
+            process::exit(2);
         }
-    };
-
-    loop {
-
-        // Iterating all the first element in each queue, and load the record from the file
+    }
+}
 
-        for i in 0..chunk_size {
-            // check the queue top whether is the empty mark
-            let mut queue = &mut queue_pool[i];
-            // Initialising the queue.
-            fill_the_queue(&mut queue, i, queue_size, &primary_key_pat, &secondary_key_pat);
+fn check_format(format: &str) -> std::result::Result<(), String> {
+    match format {
+        "gais" | "lines" | "nul" | "csv" | "tsv" | "jsonl" => Ok(()),
+        _ => match format.strip_prefix("regex:") {
+            Some(pattern) => RegexFormat::new(pattern).map(|_| ()).map_err(|error| error.to_string()),
+            None => Err(format!("unknown record format: {}", format))
         }
+    }
+}
 
+fn field_delimiter(delimiter: &str) -> std::result::Result<u8, String> {
+    match delimiter.as_bytes() {
+        b"\\t" => Ok(b'\t'),
+        [byte] if *byte != b'"' && *byte != b'\n' => Ok(*byte),
+        _ => Err(format!("invalid field delimiter: {}", delimiter))
+    }
+}
 
-
-        // 1. Pick up the record from top of queues, the initial run.
-        for i in 0..chunk_size {
-            if *external_node[i] == None {
-                let mut queue = &mut queue_pool[i];
-                let rec = queue.queue.pop_front();
-                *external_node[i] = rec;
-                queue.current_size -= match &*external_node[i] {
-                    Some(rec) => rec.record_size,
-                    None => 0
-                }
-            }
-        }
-
-        // 2. Send the winner tree array to loser tree function to choose the winner
-        let top = winner_tree_by_idx(&mut internal_node, &mut external_node);
-        rec_cnt += 1;
-        if rec_cnt % 10000 == 0 {
-            println!("{}", rec_cnt);
+/// Sorts or checks the inputs, returning whether the checked inputs are sorted.
+fn run(matches: &ArgMatches) -> Result<bool> {
+    let mut sorter = Sorter::new()
+        .record_delimiter(matches.value_of("record-begin").unwrap())
+        .input_encoding(matches.value_of("input-encoding").unwrap().parse()?)
+        .output_utf8(matches.is_present("output-utf8"))
+        .key_type(matches.value_of("key-type").unwrap().parse()?)
+        .secondary_key_type(matches.value_of("secondary-key-type").unwrap().parse()?)
+        .memory_size(parse_memory_size(matches.value_of("memory").unwrap())?)
+        .merge_buffer(parse_memory_size(matches.value_of("merge-buffer").unwrap())?)
+        .replacement_selection(matches.is_present("replacement-selection"))
+        .merge(matches.is_present("merge"))
+        .verify_sorted(matches.is_present("verify"))
+        .threads(matches.value_of("threads").unwrap().parse().unwrap_or(0))
+        .keys_only(matches.is_present("keys-only"))
+        .provenance(matches.is_present("provenance"));
+    let keys = matches.values_of("sort-key").into_iter().flatten()
+        .map(|spec| spec.parse())
+        .collect::<Result<Vec<KeySpec>>>()?;
+    // the default keys are GAIS fields, left to the format unless given; -K replaces -k and -s
+    if keys.is_empty() {
+        if matches.occurrences_of("key") > 0 {
+            sorter = sorter.key(matches.value_of("key").unwrap());
         }
-
-        match &*external_node[top] {
-            Some(rec) => {
-                let r = match &rec.record_key_value {
-                    Some(s) => s.clone(),
-                    None => "".to_string()
-                };
-                match result_file.write(r.as_bytes()) {
-                    Ok(_size) => (),
-                    Err(_e) => {panic!("Write error");}
-                }
-                match result_file.write('\n'.to_string().as_bytes()) {
-                    Ok(_size) => (),
-                    Err(_e) => {panic!("Write error");}
-                }
-            },
-            None => {
-                break;
-            }
+        if matches.occurrences_of("secondary-key") > 0 {
+            sorter = sorter.secondary_key(matches.value_of("secondary-key").unwrap());
         }
-
-        if *external_node[top] == None {
-            for i in 0..chunk_size {
-                // check the queue top whether is the empty mark
-                let queue = &mut queue_pool[i];
-                if queue.end_of_record == false {
-                    println!("INT{:?}", internal_node);
-                    println!("EXN{:?}", external_node);
-                    panic!("The queue should be empty");
-                }
-            }
-            break;
+    }
+    sorter = sorter.keys(keys);
+    for filename in matches.values_of("input").unwrap() {
+        sorter = sorter.input(filename);
+    }
+    if let Some(reject_file) = matches.value_of("reject-file") {
+        let rejects = File::create(reject_file).map_err(|error| RsortError::io(reject_file, error))?;
+        sorter = sorter.rejects(BufWriter::new(rejects));
+    }
+    if let Some(body_field) = matches.value_of("body-field") {
+        sorter = sorter.body_field(body_field);
+    }
+    match matches.value_of("format").unwrap() {
+        "gais" => (),
+        "lines" => sorter = sorter.format(DelimitedFormat::lines()),
+        "nul" => sorter = sorter.format(DelimitedFormat::nul()),
+        "jsonl" => sorter = sorter.format(JsonFormat),
+        "csv" | "tsv" => {
+            let delimiter = match matches.value_of("field-delimiter") {
+                Some(delimiter) => field_delimiter(delimiter).map_err(RsortError::Parse)?,
+                None if matches.value_of("format") == Some("tsv") => b'\t',
+                None => b','
+            };
+            sorter = sorter.format(CsvFormat::new(delimiter).header(!matches.is_present("no-header")));
+        },
+        format => match format.strip_prefix("regex:") {
+            Some(pattern) => sorter = sorter.format(RegexFormat::new(pattern)?),
+            None => return Err(RsortError::Parse(format!("unknown record format: {}", format)))
         }
-
-        *external_node[top] = None;
-
+    }
+    if matches.is_present("unique") {
+        sorter = sorter.unique(matches.value_of("survivor").unwrap_or("first").parse()?);
+    }
+    if let Some(fan_in) = matches.value_of("fan-in") {
+        sorter = sorter.fan_in(fan_in.parse().map_err(|_| RsortError::Parse(format!("invalid fan-in: {}", fan_in)))?);
+    }
+    for temp_dir in matches.values_of("temp-dir").into_iter().flatten() {
+        sorter = sorter.temp_dir(temp_dir);
     }
 
-    // clean up the file
-    for i in 0..chunk_size {
-        match remove_dir_all(format!("/tmp/rec_chunk_{}", i)) {
-            Ok(()) => {},
-            Err(_e) => {panic!("Something went wrong while deleting the tmp file.");}
+    if matches.is_present("check") {
+        let summary = sorter.check()?;
+        if let Some(disorder) = summary.disorder {
+            eprintln!("rsort: {}", disorder);
+            return Ok(false);
         }
+        eprintln!("{} records in order ({} bytes)", summary.records, summary.input_bytes);
+        return Ok(true);
     }
 
+    let output = matches.value_of("output").unwrap();
+    let summary = if output == "-" {
+        sorter.output(BufWriter::new(io::stdout())).sort()?
+    } else {
+        sort_to_file(sorter, Path::new(output))?
+    };
+    eprintln!("{} records sorted in {} runs and {} intermediate merges ({} bytes)",
+              summary.records, summary.runs, summary.intermediate_merges, summary.input_bytes);
+    if summary.dropped > 0 || summary.rejected > 0 {
+        eprintln!("{} records dropped and {} rejected for missing keys", summary.dropped, summary.rejected);
+    }
+    if summary.duplicates > 0 {
+        eprintln!("{} duplicate records left out", summary.duplicates);
+    }
+    Ok(true)
+}
 
-
+/// Sorts into a file beside `output` and renames it over `output` once the sort succeeds, so that the
+/// output may be one of the inputs, as in `rsort -o crawl.rec crawl.rec`, and a failed sort leaves
+/// the old output in place.
+fn sort_to_file(sorter: Sorter, output: &Path) -> Result<SortSummary> {
+    let name = output.file_name().ok_or_else(|| RsortError::Config(format!("not a file: {}", output.display())))?;
+    let partial = PARTIAL_OUTPUT.get_or_init(|| {
+        output.with_file_name(format!(".{}.rsort-{}", name.to_string_lossy(), process::id()))
+    });
+    let file = File::create(partial).map_err(|error| RsortError::io(partial, error))?;
+    let sorted = sorter.output(BufWriter::new(file)).sort()
+        .and_then(|summary| fs::rename(partial, output).map(|_| summary).map_err(|error| RsortError::io(output, error)));
+    if sorted.is_err() {
+        let _ = fs::remove_file(partial);
+    }
+    sorted
 }
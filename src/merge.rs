@@ -0,0 +1,160 @@
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use crate::{compare_records, fill_the_queue, KeySpec, LoserTree, Queue, RawRecord, Result, RunReader};
+
+/// A sorted stream of records the merge reads from: a run file, or an input that is sorted already.
+pub trait SortedRun {
+    /// The next record without consuming it, or `None` once the run is drained.
+    fn peek(&mut self) -> Result<Option<&RawRecord>>;
+
+    /// Takes the next record, or `None` once the run is drained.
+    fn next_record(&mut self) -> Result<Option<RawRecord>>;
+}
+
+impl SortedRun for RunReader {
+    fn peek(&mut self) -> Result<Option<&RawRecord>> {
+        RunReader::peek(self)
+    }
+
+    fn next_record(&mut self) -> Result<Option<RawRecord>> {
+        RunReader::next_record(self)
+    }
+}
+
+/// The smallest read-ahead buffer a run gets during a merge by default; the fan-in is capped so
+/// that every merged run is given at least this much of the memory budget.
+pub const DEFAULT_MERGE_BUFFER: usize = 1024 * 1024;
+
+/// The widest merge the memory budget allows when each run needs at least `merge_buffer` bytes,
+/// further capped by `fan_in` when given. At least two runs are always merged at once.
+pub fn max_fan_in(memory_size: usize, merge_buffer: usize, fan_in: Option<usize>) -> usize {
+    let by_memory = memory_size / merge_buffer.max(1);
+    match fan_in {
+        Some(fan_in) => fan_in.min(by_memory),
+        None => by_memory
+    }.max(2)
+}
+
+/// Plans the merge passes for runs of the given sizes.
+///
+/// Each step of the plan lists the runs merged into a new run; the new runs are numbered on from
+/// `run_sizes.len()`, and the last step is the final merge into the output. While there are more
+/// runs than `fan_in`, the smallest runs are merged first, and the first intermediate merge only
+/// takes as many runs as needed for every later merge to be a full `fan_in` wide.
+pub fn plan_merge_passes(run_sizes: &[usize], fan_in: usize) -> Vec<Vec<usize>> {
+    let fan_in = fan_in.max(2);
+    let mut runs: Vec<(usize, usize)> = run_sizes.iter().cloned().enumerate().collect(); // (id, size)
+    let mut next_id = run_sizes.len();
+    let mut plan = Vec::new();
+
+    while runs.len() > fan_in {
+        let width = (runs.len() - 2) % (fan_in - 1) + 2;
+        runs.sort_by(|a, b| a.1.cmp(&b.1).then(a.0.cmp(&b.0)));
+        let mut step: Vec<(usize, usize)> = runs.drain(..width).collect();
+        step.sort();
+        runs.push((next_id, step.iter().map(|run| run.1).sum()));
+        next_id += 1;
+        plan.push(step.into_iter().map(|run| run.0).collect());
+    }
+    runs.sort();
+    plan.push(runs.into_iter().map(|run| run.0).collect());
+    plan
+}
+
+/// Compares the heads of two runs, a drained run sorting after every record. Records with equal
+/// keys come out in input order, whichever runs they were merged into, so the output does not
+/// depend on how the input was cut into runs nor on how many passes merged them.
+fn compare_heads(a: Option<&RawRecord>, b: Option<&RawRecord>, keys: &[KeySpec]) -> Ordering {
+    match (a, b) {
+        (Some(a), Some(b)) => compare_records(a, b, keys).then_with(|| a.origin.cmp(&b.origin)),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal
+    }
+}
+
+/// Takes the next record of a run, refilling its queue from the run file once the queue runs dry.
+fn next_in_queue<R: SortedRun>(queue: &mut Queue, run: &mut R, queue_size: usize) -> Result<Option<RawRecord>> {
+    if queue.queue.is_empty() {
+        fill_the_queue(queue, run, queue_size)?;
+    }
+    let rec = queue.queue.pop_front();
+    if let Some(rec) = &rec {
+        queue.current_size -= rec.record_size;
+    }
+    Ok(rec)
+}
+
+/// Performs a K-way merge of the runs, handing the records to `emit` in sorted order.
+///
+/// The loser tree replays only the path of the run whose head was taken, and every run gets an
+/// equal share of the memory budget as its read-ahead queue.
+pub fn merge_runs<R, F>(run_pool: &mut [R], keys: &[KeySpec], memory_size: usize, mut emit: F) -> Result<()>
+    where R: SortedRun, F: FnMut(RawRecord) -> Result<()> {
+    let chunk_size = run_pool.len(); // chunk_size, or called K-way
+    let queue_size: usize = (memory_size as f64 / chunk_size.max(1) as f64).ceil() as usize;
+
+    let record_queue = Queue {
+        queue: VecDeque::new(),
+        current_size: 0,
+        record_cnt: 0,
+        end_of_record: false
+    };
+    let mut queue_pool: Vec<Queue> = vec![record_queue; chunk_size];
+
+    // the head of each run, None once the run is drained
+    let mut heads: Vec<Option<RawRecord>> = Vec::with_capacity(chunk_size);
+    for (queue, run) in queue_pool.iter_mut().zip(run_pool.iter_mut()) {
+        heads.push(next_in_queue(queue, run, queue_size)?);
+    }
+    let mut tree = LoserTree::new(chunk_size, |a, b| compare_heads(heads[a].as_ref(), heads[b].as_ref(), keys));
+
+    while !tree.is_empty() {
+        let top = tree.winner();
+        let rec = match heads[top].take() {
+            Some(rec) => rec,
+            None => break // the drained runs sort last, so every run is drained
+        };
+        emit(rec)?;
+
+        heads[top] = next_in_queue(&mut queue_pool[top], &mut run_pool[top], queue_size)?;
+        tree.replay(top, |a, b| compare_heads(heads[a].as_ref(), heads[b].as_ref(), keys));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fan_in_follows_memory() {
+        assert_eq!(max_fan_in(512 * 1024 * 1024, DEFAULT_MERGE_BUFFER, None), 512);
+        assert_eq!(max_fan_in(512 * 1024 * 1024, DEFAULT_MERGE_BUFFER, Some(16)), 16);
+        assert_eq!(max_fan_in(1024, DEFAULT_MERGE_BUFFER, Some(16)), 2);
+    }
+
+    #[test]
+    fn single_pass_when_runs_fit() {
+        assert_eq!(plan_merge_passes(&[10, 20, 30], 4), vec![vec![0, 1, 2]]);
+        assert_eq!(plan_merge_passes(&[10], 4), vec![vec![0]]);
+    }
+
+    #[test]
+    fn smallest_runs_merge_first() {
+        // 5 runs with a fan-in of 4: merging the two smallest leaves a full final merge
+        let plan = plan_merge_passes(&[50, 10, 40, 20, 30], 4);
+        assert_eq!(plan, vec![vec![1, 3], vec![0, 2, 4, 5]]);
+
+        // every merge stays within the fan-in and every run is merged exactly once
+        let plan = plan_merge_passes(&[7; 23], 3);
+        let mut merged = vec![0; 23 + plan.len() - 1];
+        for step in plan.iter() {
+            assert!(step.len() <= 3);
+            for run in step.iter() {
+                merged[*run] += 1;
+            }
+        }
+        assert!(merged.iter().all(|cnt| *cnt == 1));
+    }
+}
@@ -0,0 +1,204 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use crate::{Key, RawRecord, RecordOrigin, Result, RsortError};
+
+/// Every run file opens with this header, so a stray file is not mistaken for a run.
+const RUN_MAGIC: &[u8; 8] = b"RSORTRUN";
+
+/// Writes a sorted run as one sequential file.
+///
+/// Each record is stored as its length, its origin as an input index and a byte offset, the number
+/// of its keys, the keys and then its raw bytes, all numbers being little-endian `u64`, so the merge
+/// never has to extract the keys again.
+pub struct RunWriter {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    record_cnt: usize,
+}
+
+impl RunWriter {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<RunWriter> {
+        let path = path.as_ref().to_path_buf();
+        let file = File::create(&path).map_err(|error| RsortError::io(&path, error))?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(RUN_MAGIC).map_err(|error| RsortError::io(&path, error))?;
+        Ok(RunWriter { path, writer, record_cnt: 0 })
+    }
+
+    pub fn write_record(&mut self, record: &RawRecord) -> Result<()> {
+        let writer = &mut self.writer;
+        let written = writer.write_all(&(record.raw_record.len() as u64).to_le_bytes())
+            .and_then(|_| writer.write_all(&(record.origin.file as u64).to_le_bytes()))
+            .and_then(|_| writer.write_all(&record.origin.offset.to_le_bytes()))
+            .and_then(|_| writer.write_all(&(record.record_keys.len() as u64).to_le_bytes()))
+            .and_then(|_| record.record_keys.iter().try_for_each(|key| write_key(writer, key)))
+            .and_then(|_| writer.write_all(&record.raw_record));
+        written.map_err(|error| RsortError::io(&self.path, error))?;
+        self.record_cnt += 1;
+        Ok(())
+    }
+
+    /// Flushes the run to disk and returns the number of records written.
+    pub fn finish(mut self) -> Result<usize> {
+        self.writer.flush().map_err(|error| RsortError::io(&self.path, error))?;
+        Ok(self.record_cnt)
+    }
+}
+
+fn write_key<W: Write>(writer: &mut W, key: &Option<Key>) -> std::io::Result<()> {
+    match key {
+        Some(key) => {
+            let key = key.to_bytes();
+            writer.write_all(&[1])?;
+            writer.write_all(&(key.len() as u64).to_le_bytes())?;
+            writer.write_all(&key)
+        },
+        None => writer.write_all(&[0])
+    }
+}
+
+/// Streams the records of a run written by [`RunWriter`] back in order.
+pub struct RunReader {
+    path: PathBuf,
+    reader: BufReader<File>,
+    pending: Option<RawRecord>,
+}
+
+impl RunReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<RunReader> {
+        let path = path.as_ref().to_path_buf();
+        let file = File::open(&path).map_err(|error| RsortError::io(&path, error))?;
+        let mut reader = RunReader { path, reader: BufReader::new(file), pending: None };
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != RUN_MAGIC {
+            return Err(reader.corrupted("the run header is missing"));
+        }
+        Ok(reader)
+    }
+
+    /// The next record of the run without consuming it, or `None` once the run is drained.
+    pub fn peek(&mut self) -> Result<Option<&RawRecord>> {
+        if self.pending.is_none() {
+            self.pending = self.read_record()?;
+        }
+        Ok(self.pending.as_ref())
+    }
+
+    /// Takes the next record of the run, or `None` once the run is drained.
+    pub fn next_record(&mut self) -> Result<Option<RawRecord>> {
+        match self.pending.take() {
+            Some(record) => Ok(Some(record)),
+            None => self.read_record()
+        }
+    }
+
+    fn read_record(&mut self) -> Result<Option<RawRecord>> {
+        let at_end = match self.reader.fill_buf() {
+            Ok(buf) => buf.is_empty(),
+            Err(error) => return Err(RsortError::io(&self.path, error))
+        };
+        if at_end {
+            return Ok(None);
+        }
+        let record_size = self.read_len()?;
+        let origin = RecordOrigin { file: self.read_len()?, offset: self.read_len()? as u64 };
+        let key_cnt = self.read_len()?;
+        let mut record_keys = Vec::with_capacity(key_cnt.min(64));
+        for _ in 0..key_cnt {
+            record_keys.push(self.read_key()?);
+        }
+        let raw_record = self.read_bytes(record_size)?;
+        Ok(Some(RawRecord {
+            record_keys,
+            raw_record,
+            record_size,
+            origin,
+        }))
+    }
+
+    fn read_key(&mut self) -> Result<Option<Key>> {
+        let mut tag = [0u8; 1];
+        self.read_exact(&mut tag)?;
+        match tag[0] {
+            0 => Ok(None),
+            1 => {
+                let len = self.read_len()?;
+                let key = self.read_bytes(len)?;
+                match Key::from_bytes(&key) {
+                    Some(key) => Ok(Some(key)),
+                    None => Err(self.corrupted("invalid key"))
+                }
+            },
+            _ => Err(self.corrupted("invalid key tag"))
+        }
+    }
+
+    fn read_len(&mut self) -> Result<usize> {
+        let mut len = [0u8; 8];
+        self.read_exact(&mut len)?;
+        Ok(u64::from_le_bytes(len) as usize)
+    }
+
+    fn read_bytes(&mut self, len: usize) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        let read = (&mut self.reader).take(len as u64).read_to_end(&mut buf)
+            .map_err(|error| RsortError::io(&self.path, error))?;
+        if read != len {
+            return Err(self.corrupted("the run ends in the middle of a record"));
+        }
+        Ok(buf)
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
+        match self.reader.read_exact(buf) {
+            Ok(()) => Ok(()),
+            Err(ref error) if error.kind() == ErrorKind::UnexpectedEof =>
+                Err(self.corrupted("the run ends in the middle of a record")),
+            Err(error) => Err(RsortError::io(&self.path, error))
+        }
+    }
+
+    fn corrupted(&self, detail: &str) -> RsortError {
+        RsortError::CorruptedRun { path: self.path.clone(), detail: detail.to_string() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{GaisFormat, InputEncoding, KeySpec, KeyType, RecordOrigin};
+
+    #[test]
+    fn run_round_trip() {
+        let path = std::env::temp_dir().join(format!("rsort_run_test_{}.run", std::process::id()));
+        let keys = [KeySpec::new("@url:", KeyType::Text), KeySpec::new("@Size:", KeyType::Integer)];
+        let records = [
+            RawRecord::parse_raw_record(b"@\n@Gais_REC:\n@url:http://a\n".to_vec(), RecordOrigin::default(), &keys, &GaisFormat::default(), InputEncoding::Raw, false),
+            RawRecord::parse_raw_record(b"@\n@Gais_REC:\n@url:http://\xa5\x78\n@Size:89230\n".to_vec(), RecordOrigin { file: 1, offset: 28 }, &keys, &GaisFormat::default(), InputEncoding::Raw, false),
+        ];
+        let mut writer = RunWriter::create(&path).unwrap();
+        for record in records.iter() {
+            writer.write_record(record).unwrap();
+        }
+        assert_eq!(writer.finish().unwrap(), 2);
+
+        let mut reader = RunReader::open(&path).unwrap();
+        assert_eq!(reader.peek().unwrap(), Some(&records[0]));
+        assert_eq!(reader.next_record().unwrap().as_ref(), Some(&records[0]));
+        assert_eq!(reader.next_record().unwrap().as_ref(), Some(&records[1]));
+        assert_eq!(reader.next_record().unwrap(), None);
+
+        // a run cut short is reported instead of silently ending
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &bytes[..bytes.len() - 3]).unwrap();
+        let mut reader = RunReader::open(&path).unwrap();
+        reader.next_record().unwrap();
+        match reader.next_record() {
+            Err(RsortError::CorruptedRun { .. }) => (),
+            other => panic!("expected a corrupted run, got {:?}", other)
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
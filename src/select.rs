@@ -0,0 +1,178 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use crate::{compare_records, KeySpec, RawRecord, Result, RunWriter, TempDirs};
+
+/// A record waiting in the replacement-selection heap, tagged with the run it goes to.
+struct Candidate<'a> {
+    run: usize,
+    seq: usize,
+    record: RawRecord,
+    keys: &'a [KeySpec],
+}
+
+impl<'a> Ord for Candidate<'a> {
+    // BinaryHeap pops the greatest element, so the order is reversed to pop the smallest record
+    // of the earliest run; `seq` keeps records with equal keys in input order
+    fn cmp(&self, other: &Candidate<'a>) -> Ordering {
+        other.run.cmp(&self.run)
+            .then_with(|| compare_records(&other.record, &self.record, self.keys))
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl<'a> PartialOrd for Candidate<'a> {
+    fn partial_cmp(&self, other: &Candidate<'a>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<'a> PartialEq for Candidate<'a> {
+    fn eq(&self, other: &Candidate<'a>) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<'a> Eq for Candidate<'a> {}
+
+/// Generates runs by replacement selection.
+///
+/// The records are kept in a heap as large as the memory budget. Once the heap is full, its smallest
+/// record is written to the current run and replaced by the incoming one; an incoming record that
+/// sorts before the last record written has to wait for the next run. On random input the runs
+/// come out about twice as long as the memory budget, and an already sorted input is a single run.
+pub struct ReplacementSelection<'a> {
+    temp_dirs: &'a TempDirs,
+    keys: &'a [KeySpec],
+    memory_size: usize,
+    heap: BinaryHeap<Candidate<'a>>,
+    heap_size: usize,
+    seq: usize,
+    run: Option<RunWriter>,
+    last: Option<RawRecord>,
+    run_sizes: Vec<usize>,
+}
+
+impl<'a> ReplacementSelection<'a> {
+    pub fn new(memory_size: usize, keys: &'a [KeySpec], temp_dirs: &'a TempDirs) -> ReplacementSelection<'a> {
+        ReplacementSelection {
+            temp_dirs,
+            keys,
+            memory_size,
+            heap: BinaryHeap::new(),
+            heap_size: 0,
+            seq: 0,
+            run: None,
+            last: None,
+            run_sizes: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, record: RawRecord) -> Result<()> {
+        while !self.heap.is_empty() && self.heap_size + record.record_size > self.memory_size {
+            self.emit()?;
+        }
+        let current_run = self.run_sizes.len().saturating_sub(1);
+        let run = match &self.last {
+            Some(last) if compare_records(&record, last, self.keys) == Ordering::Less => current_run + 1,
+            _ => current_run
+        };
+        self.heap_size += record.record_size;
+        self.heap.push(Candidate { run, seq: self.seq, record, keys: self.keys });
+        self.seq += 1;
+        Ok(())
+    }
+
+    /// Writes the smallest record of the heap to its run, starting the run when needed.
+    fn emit(&mut self) -> Result<()> {
+        let candidate = match self.heap.pop() {
+            Some(candidate) => candidate,
+            None => return Ok(())
+        };
+        self.heap_size -= candidate.record.record_size;
+        if self.run.is_none() || candidate.run >= self.run_sizes.len() {
+            if let Some(run) = self.run.take() {
+                run.finish()?;
+            }
+            self.run = Some(RunWriter::create(self.temp_dirs.run_path(self.run_sizes.len()))?);
+            self.run_sizes.push(0);
+        }
+        if let Some(run) = self.run.as_mut() {
+            run.write_record(&candidate.record)?;
+        }
+        if let Some(run_size) = self.run_sizes.last_mut() {
+            *run_size += candidate.record.record_size;
+        }
+        self.last = Some(candidate.record);
+        Ok(())
+    }
+
+    /// Drains the heap into the runs and returns the size of every run.
+    pub fn finish(mut self) -> Result<Vec<usize>> {
+        while !self.heap.is_empty() {
+            self.emit()?;
+        }
+        if let Some(run) = self.run.take() {
+            run.finish()?;
+        }
+        Ok(self.run_sizes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{GaisFormat, InputEncoding, Key, RecordOrigin, KeySpec, KeyType, RunReader};
+
+    fn url_key() -> Vec<KeySpec> {
+        vec![KeySpec::new("@url:", KeyType::Integer)]
+    }
+
+    fn record(url: usize) -> RawRecord {
+        RawRecord::parse_raw_record(format!("@\n@Gais_REC:\n@url:{}\n", url).into_bytes(), RecordOrigin::default(), &url_key(), &GaisFormat::default(), InputEncoding::Raw, false)
+    }
+
+    fn run_urls(temp_dirs: &TempDirs, run: usize) -> Vec<Key> {
+        let mut reader = RunReader::open(temp_dirs.run_path(run)).unwrap();
+        let mut urls = Vec::new();
+        while let Some(record) = reader.next_record().unwrap() {
+            urls.push(record.record_keys[0].clone().unwrap());
+        }
+        urls
+    }
+
+    #[test]
+    fn sorted_input_is_a_single_run() {
+        let temp_dirs = TempDirs::create(&[]).unwrap();
+        let memory_size = record(0).record_size * 4;
+        let keys = url_key();
+        let mut selection = ReplacementSelection::new(memory_size, &keys, &temp_dirs);
+        for url in 0..100 {
+            selection.push(record(url)).unwrap();
+        }
+        assert_eq!(selection.finish().unwrap().len(), 1);
+        let urls = run_urls(&temp_dirs, 0);
+        assert_eq!(urls.len(), 100);
+        assert!(urls.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[test]
+    fn runs_outgrow_the_memory() {
+        let temp_dirs = TempDirs::create(&[]).unwrap();
+        let memory_size = record(0).record_size * 8;
+        let keys = url_key();
+        let mut selection = ReplacementSelection::new(memory_size, &keys, &temp_dirs);
+        // a fixed pseudo-random permutation
+        for i in 0..200 {
+            selection.push(record(i * 37 % 200)).unwrap();
+        }
+        let run_sizes = selection.finish().unwrap();
+        assert!(run_sizes.len() < 200 / 8);
+        let mut total = 0;
+        for run in 0..run_sizes.len() {
+            let urls = run_urls(&temp_dirs, run);
+            assert!(urls.windows(2).all(|pair| pair[0] <= pair[1]));
+            total += urls.len();
+        }
+        assert_eq!(total, 200);
+    }
+}
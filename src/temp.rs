@@ -0,0 +1,108 @@
+use std::fs::{create_dir, create_dir_all, remove_dir_all};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::{Result, RsortError};
+
+/// Every working directory still alive in this process, so a signal handler can remove them.
+static LIVE_DIRS: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Tells apart the working directories of several sorts in the same process.
+static SORT_CNT: AtomicUsize = AtomicUsize::new(0);
+
+/// The private working directories a sort spills its runs to.
+///
+/// One uniquely named directory is created under each base directory, so concurrent sorts never
+/// share files, and the runs are spread over them round-robin to balance the I/O across disks.
+/// The directories are removed when this value is dropped, whether the sort succeeded or not.
+#[derive(Debug)]
+pub struct TempDirs {
+    dirs: Vec<PathBuf>,
+}
+
+impl TempDirs {
+    /// Creates a working directory under each of `bases`, or under the system temporary
+    /// directory (`TMPDIR`, falling back to `/tmp`) when `bases` is empty.
+    pub fn create(bases: &[PathBuf]) -> Result<TempDirs> {
+        let default_base = [std::env::temp_dir()];
+        let bases = if bases.is_empty() { &default_base[..] } else { bases };
+        let mut temp_dirs = TempDirs { dirs: Vec::with_capacity(bases.len()) };
+        for base in bases {
+            let dir = create_unique_dir(base)?;
+            LIVE_DIRS.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push(dir.clone());
+            temp_dirs.dirs.push(dir);
+        }
+        Ok(temp_dirs)
+    }
+
+    /// The file holding the sorted records of the `chunk`-th run.
+    pub fn run_path(&self, chunk: usize) -> PathBuf {
+        self.dirs[chunk % self.dirs.len()].join(format!("rec_chunk_{}.run", chunk))
+    }
+
+    /// The working directories, one per base directory.
+    pub fn dirs(&self) -> &[PathBuf] {
+        &self.dirs
+    }
+}
+
+impl Drop for TempDirs {
+    fn drop(&mut self) {
+        let mut live_dirs = LIVE_DIRS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        for dir in self.dirs.iter() {
+            let _ = remove_dir_all(dir);
+            live_dirs.retain(|live_dir| live_dir != dir);
+        }
+    }
+}
+
+fn create_unique_dir(base: &Path) -> Result<PathBuf> {
+    create_dir_all(base).map_err(|error| RsortError::io(base, error))?;
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.subsec_nanos()).unwrap_or(0);
+    loop {
+        let dir = base.join(format!("rsort.{}.{}.{:09}", process::id(), SORT_CNT.fetch_add(1, Ordering::SeqCst), nanos));
+        match create_dir(&dir) {
+            Ok(()) => return Ok(dir),
+            Err(ref error) if error.kind() == ErrorKind::AlreadyExists => continue,
+            Err(error) => return Err(RsortError::io(&dir, error))
+        }
+    }
+}
+
+/// Removes the working directories of every sort still running in this process.
+///
+/// Meant for signal handlers such as Ctrl-C, where the sorts are interrupted before they get to
+/// clean up after themselves.
+pub fn remove_temp_dirs() {
+    let mut live_dirs = LIVE_DIRS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    for dir in live_dirs.drain(..) {
+        let _ = remove_dir_all(&dir);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn temp_dirs_are_unique_and_removed() {
+        let base = std::env::temp_dir().join(format!("rsort_temp_test_{}", process::id()));
+        let bases = vec![base.join("a"), base.join("b")];
+        let first = TempDirs::create(&bases).unwrap();
+        let second = TempDirs::create(&bases).unwrap();
+        assert_ne!(first.dirs(), second.dirs());
+        assert!(first.run_path(0).starts_with(&first.dirs()[0]));
+        assert!(first.run_path(1).starts_with(&first.dirs()[1]));
+        assert!(first.run_path(2).starts_with(&first.dirs()[0]));
+
+        let dirs = first.dirs().to_vec();
+        drop(first);
+        assert!(dirs.iter().all(|dir| !dir.exists()));
+        assert!(second.dirs().iter().all(|dir| dir.exists()));
+        drop(second);
+        remove_dir_all(&base).unwrap();
+    }
+}
@@ -0,0 +1,113 @@
+use std::cmp::Ordering;
+
+/// A loser (tournament) tree over `k` runs for the K-way merge.
+///
+/// The tree only stores run indices: `losers[0]` is the overall winner and `losers[1..k]` hold the
+/// loser of the match played at each internal node. Leaf `i` sits at position `k + i`, so any `k`
+/// works, not only powers of two. The records themselves stay with the caller, which hands in a
+/// comparator over run indices; a drained run must compare greater than every live one.
+#[derive(Clone, Debug)]
+pub struct LoserTree {
+    losers: Vec<usize>,
+}
+
+impl LoserTree {
+    /// Plays the initial tournament between the heads of all `k` runs.
+    pub fn new<F>(k: usize, mut cmp: F) -> LoserTree
+        where F: FnMut(usize, usize) -> Ordering {
+        if k == 0 {
+            return LoserTree { losers: Vec::new() };
+        }
+        // winners[n] is the winner of the sub-tree rooted at n, leaves included
+        let mut winners = vec![0; 2 * k];
+        let mut losers = vec![0; k];
+        for i in 0..k {
+            winners[k + i] = i;
+        }
+        for node in (1..k).rev() {
+            let (left, right) = (winners[2 * node], winners[2 * node + 1]);
+            if beats(left, right, &mut cmp) {
+                winners[node] = left;
+                losers[node] = right;
+            } else {
+                winners[node] = right;
+                losers[node] = left;
+            }
+        }
+        losers[0] = winners[1];
+        LoserTree { losers }
+    }
+
+    /// The number of runs in the tournament.
+    pub fn len(&self) -> usize {
+        self.losers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.losers.is_empty()
+    }
+
+    /// The run holding the smallest head.
+    pub fn winner(&self) -> usize {
+        self.losers[0]
+    }
+
+    /// Replays the matches on the path from `run`'s leaf to the root after its head changed,
+    /// which costs O(log k) comparisons.
+    pub fn replay<F>(&mut self, run: usize, mut cmp: F)
+        where F: FnMut(usize, usize) -> Ordering {
+        let k = self.losers.len();
+        let mut winner = run;
+        let mut node = (k + run) / 2;
+        while node > 0 {
+            if beats(self.losers[node], winner, &mut cmp) {
+                std::mem::swap(&mut self.losers[node], &mut winner);
+            }
+            node /= 2;
+        }
+        self.losers[0] = winner;
+    }
+}
+
+/// Whether run `a` wins over run `b`; ties go to the earlier run so the merge is stable.
+fn beats<F>(a: usize, b: usize, cmp: &mut F) -> bool
+    where F: FnMut(usize, usize) -> Ordering {
+    match cmp(a, b) {
+        Ordering::Less => true,
+        Ordering::Equal => a < b,
+        Ordering::Greater => false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_any_number_of_runs() {
+        for k in 1..10 {
+            let runs: Vec<Vec<usize>> = (0..k).map(|r| (0..r + 3).map(|i| i * k + (r * 7) % k).collect()).collect();
+            let mut positions = vec![0; k];
+            let head = |positions: &Vec<usize>, r: usize| runs[r].get(positions[r]).cloned();
+            let cmp = |positions: &Vec<usize>, a: usize, b: usize| match (head(positions, a), head(positions, b)) {
+                (Some(a), Some(b)) => a.cmp(&b),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => Ordering::Equal
+            };
+
+            let mut tree = LoserTree::new(k, |a, b| cmp(&positions, a, b));
+            let mut merged = Vec::new();
+            while let Some(value) = head(&positions, tree.winner()) {
+                merged.push(value);
+                let run = tree.winner();
+                positions[run] += 1;
+                tree.replay(run, |a, b| cmp(&positions, a, b));
+            }
+
+            let mut expected: Vec<usize> = runs.iter().flatten().cloned().collect();
+            expected.sort();
+            assert_eq!(merged, expected, "k = {}", k);
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+use crate::{compare_records, merge_runs, KeySpec, RawRecord, Result, RsortError, SortedRun};
+
+/// Which record survives of the records sharing their sort keys in unique mode.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Survivor {
+    /// The record that comes first in the input.
+    First,
+    /// The record that comes last in the input.
+    Last,
+    /// The record with the greatest value of another field in the order of its key, such as the
+    /// latest `@Fetchtime:`; a record holding the field wins over one without it.
+    Max(KeySpec),
+    /// The record with the smallest value of another field, like [`Survivor::Max`].
+    Min(KeySpec),
+}
+
+impl Survivor {
+    /// The key of the field deciding between the records, extracted after the sort keys.
+    pub fn key(&self) -> Option<&KeySpec> {
+        match self {
+            Survivor::First | Survivor::Last => None,
+            Survivor::Max(key) | Survivor::Min(key) => Some(key),
+        }
+    }
+
+    /// Whether `record` survives over `kept`, both having the same sort keys; `index` is where the
+    /// survivor's key sits among their keys. Ties go to the record first in the input.
+    fn prefers(&self, record: &RawRecord, kept: &RawRecord, index: usize) -> bool {
+        let first = record.origin < kept.origin;
+        let (key, wanted) = match self {
+            Survivor::First => return first,
+            Survivor::Last => return !first,
+            Survivor::Max(key) => (key, Ordering::Greater),
+            Survivor::Min(key) => (key, Ordering::Less),
+        };
+        let value = |record: &RawRecord| record.record_keys.get(index).cloned().flatten();
+        match (value(record), value(kept)) {
+            (Some(a), Some(b)) => match key.compare(&Some(a), &Some(b)) {
+                Ordering::Equal => first,
+                ordering => ordering == wanted
+            },
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (None, None) => first,
+        }
+    }
+}
+
+impl FromStr for Survivor {
+    type Err = RsortError;
+
+    /// Reads `first`, `last`, `max:KEY` or `min:KEY`, the key given as a sort key such as
+    /// `@Fetchtime:,time`.
+    fn from_str(survivor: &str) -> Result<Survivor> {
+        match survivor {
+            "first" => Ok(Survivor::First),
+            "last" => Ok(Survivor::Last),
+            _ => if let Some(key) = survivor.strip_prefix("max:") {
+                Ok(Survivor::Max(key.parse()?))
+            } else if let Some(key) = survivor.strip_prefix("min:") {
+                Ok(Survivor::Min(key.parse()?))
+            } else {
+                Err(RsortError::Parse(format!("unknown survivor policy: {}", survivor)))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Survivor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Survivor::First => write!(f, "first"),
+            Survivor::Last => write!(f, "last"),
+            Survivor::Max(key) => write!(f, "max:{}", key),
+            Survivor::Min(key) => write!(f, "min:{}", key),
+        }
+    }
+}
+
+/// Keeps one record of every group with the same sort keys, the records coming in sorted order so
+/// that a group is over once the keys change.
+pub struct Dedup<'a, R> {
+    keys: &'a [KeySpec],
+    survivor: &'a Survivor,
+    kept: Option<R>,
+}
+
+impl<'a, R: Borrow<RawRecord>> Dedup<'a, R> {
+    pub fn new(keys: &'a [KeySpec], survivor: &'a Survivor) -> Dedup<'a, R> {
+        Dedup { keys, survivor, kept: None }
+    }
+
+    /// Takes the next record, returning the survivor of the previous group once the record starts
+    /// a new one.
+    pub fn push(&mut self, record: R) -> Option<R> {
+        let kept = match self.kept.take() {
+            Some(kept) => kept,
+            None => {
+                self.kept = Some(record);
+                return None;
+            }
+        };
+        if compare_records(record.borrow(), kept.borrow(), self.keys) != Ordering::Equal {
+            self.kept = Some(record);
+            return Some(kept);
+        }
+        self.kept = Some(if self.survivor.prefers(record.borrow(), kept.borrow(), self.keys.len()) { record } else { kept });
+        None
+    }
+
+    /// Returns the survivor of the last group.
+    pub fn finish(&mut self) -> Option<R> {
+        self.kept.take()
+    }
+}
+
+/// Merges the runs as [`merge_runs`] does, handing on only the survivor of every group of records
+/// with the same sort keys when `unique` is given.
+pub(crate) fn merge_unique<R, F>(run_pool: &mut [R], keys: &[KeySpec], memory_size: usize, unique: Option<&Survivor>,
+                                 mut emit: F) -> Result<()>
+    where R: SortedRun, F: FnMut(RawRecord) -> Result<()> {
+    let survivor = match unique {
+        Some(survivor) => survivor,
+        None => return merge_runs(run_pool, keys, memory_size, emit)
+    };
+    let mut dedup = Dedup::new(keys, survivor);
+    merge_runs(run_pool, keys, memory_size, |record| match dedup.push(record) {
+        Some(kept) => emit(kept),
+        None => Ok(())
+    })?;
+    match dedup.finish() {
+        Some(kept) => emit(kept),
+        None => Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{GaisFormat, InputEncoding, KeyType, RecordOrigin};
+
+    fn survivors(survivor: &str, records: &[(&str, u64)]) -> Vec<u64> {
+        let survivor: Survivor = survivor.parse().unwrap();
+        let mut keys = vec![KeySpec::new("@url:", KeyType::Text)];
+        keys.extend(survivor.key().cloned());
+        let mut dedup = Dedup::new(&keys[..1], &survivor);
+        let mut kept = Vec::new();
+        for (record, offset) in records {
+            let origin = RecordOrigin { file: 0, offset: *offset };
+            let record = RawRecord::parse_raw_record(record.as_bytes().to_vec(), origin, &keys, &GaisFormat::default(),
+                                                     InputEncoding::Raw, false);
+            kept.extend(dedup.push(record));
+        }
+        kept.extend(dedup.finish());
+        kept.into_iter().map(|record| record.origin.offset).collect()
+    }
+
+    #[test]
+    fn one_record_survives_per_key() {
+        let records = [
+            ("@url:a\n@Fetchtime:2017/01/02 00:00:00\n", 0),
+            ("@url:a\n@Fetchtime:2017/01/03 00:00:00\n", 1),
+            ("@url:a\n", 2),
+            ("@url:b\n@Fetchtime:2017/01/01 00:00:00\n", 3),
+            ("@url:c\n", 4),
+            ("@url:c\n", 5),
+        ];
+        assert_eq!(survivors("first", &records), vec![0, 3, 4]);
+        assert_eq!(survivors("last", &records), vec![2, 3, 5]);
+        assert_eq!(survivors("max:@Fetchtime:,time", &records), vec![1, 3, 4]);
+        assert_eq!(survivors("min:@Fetchtime:,time", &records), vec![0, 3, 4]);
+        assert_eq!("max:@Fetchtime:,time".parse::<Survivor>().unwrap().to_string(), "max:@Fetchtime:,time:%Y/%m/%d %H:%M:%S,asc");
+        assert!("newest".parse::<Survivor>().is_err());
+    }
+}